@@ -1,19 +1,77 @@
 use crate::world::WORLD_RESOLUTION;
+use bevy::log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
 
 const DEFAULT_CHUNK_RENDER_DISTANCE: i32 = 8;
+const DEFAULT_CHUNK_UNLOAD_DISTANCE: i32 = DEFAULT_CHUNK_RENDER_DISTANCE + 2;
+const DEFAULT_CHUNK_UNLOAD_DELAY: f32 = 5.0;
+const DEFAULT_CHUNK_CACHE_CAPACITY: usize = 256;
+const DEFAULT_CHUNK_WORK_BUDGET_MS: f32 = 8.0;
+const DEFAULT_CHUNK_LOOKAHEAD: f32 = 1.0;
+const DEFAULT_CHUNK_MAX_LOOKAHEAD: f32 = 16.0;
+const DEFAULT_LOD_DISTANCES: [i32; LOD_LEVELS] = [4, 8, 16];
 const DEFAULT_MOVEMENT_SPEED: f32 = 50.0;
 
+const DEFAULT_LEVEL_SEED: i32 = 0;
+const DEFAULT_GROUND_LEVEL: f32 = 100.0;
+const DEFAULT_NOISE_GROUND_MAX_OFFSET: f32 = 50.0;
+const DEFAULT_SEA_LEVEL: f32 = 90.0;
+
+pub const WORLD_GEN_CONFIG_PATH: &str = "world_gen.ron";
+
+/// Number of downsampled levels of detail chunks are meshed with
+pub const LOD_LEVELS: usize = 3;
+
 pub struct PlayerConfig {
   // radius of chunks around the player to render
   pub chunk_render_distance: i32,
+  // radius of chunks around the player kept loaded, larger than the render
+  // distance so that moving along a chunk border doesn't reload chunks
+  pub chunk_unload_distance: i32,
   pub camera_distance: f32,
+  // chunk distances from the player at which meshes switch to 2x, 4x and 8x
+  // downsampled voxels
+  pub lod_distances: [i32; LOD_LEVELS],
 }
 
 impl Default for PlayerConfig {
   fn default() -> Self {
     Self {
       chunk_render_distance: DEFAULT_CHUNK_RENDER_DISTANCE,
+      chunk_unload_distance: DEFAULT_CHUNK_UNLOAD_DISTANCE,
       camera_distance: 10.0,
+      lod_distances: DEFAULT_LOD_DISTANCES,
+    }
+  }
+}
+
+pub struct ChunkStreamingConfig {
+  /// Seconds a chunk has to stay out of range of every loader before it is
+  /// unloaded
+  pub unload_delay: f32,
+  /// Number of unloaded chunks kept in memory
+  pub cache_capacity: usize,
+  /// Milliseconds of generation and meshing work started per frame, as
+  /// measured on the task pool
+  pub work_budget_ms: f32,
+  /// Seconds of movement ahead of a loader for which chunks are requested in
+  /// advance
+  pub lookahead: f32,
+  /// Farthest a loader's load area is extended along its travel vector, in
+  /// chunks
+  pub max_lookahead: f32,
+}
+
+impl Default for ChunkStreamingConfig {
+  fn default() -> Self {
+    Self {
+      unload_delay: DEFAULT_CHUNK_UNLOAD_DELAY,
+      cache_capacity: DEFAULT_CHUNK_CACHE_CAPACITY,
+      work_budget_ms: DEFAULT_CHUNK_WORK_BUDGET_MS,
+      lookahead: DEFAULT_CHUNK_LOOKAHEAD,
+      max_lookahead: DEFAULT_CHUNK_MAX_LOOKAHEAD,
     }
   }
 }
@@ -31,3 +89,108 @@ impl Default for MovementSettings {
     }
   }
 }
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(std::io::Error),
+  Parse(ron::Error),
+}
+
+impl From<std::io::Error> for ConfigError {
+  fn from(err: std::io::Error) -> Self {
+    ConfigError::Io(err)
+  }
+}
+
+impl From<ron::Error> for ConfigError {
+  fn from(err: ron::Error) -> Self {
+    ConfigError::Parse(err)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TerrainKind {
+  /// 2D heightmap, every column is solid up to its surface height
+  Heightmap,
+  /// 3D density field allowing caves and overhangs
+  Density,
+  /// Grass filled up to `base_height`
+  Flat,
+  /// Flat world of alternating colours up to `base_height`, showing chunk
+  /// borders
+  Checkerboard,
+}
+
+/// Parameters of the terrain noise used by the chunk generator.
+/// Missing fields in a config file fall back to their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldGenConfig {
+  pub terrain: TerrainKind,
+  pub seed: i32,
+  pub octaves: u8,
+  pub frequency: f32,
+  pub lacunarity: f32,
+  pub gain: f32,
+  /// Height (in voxels) around which the terrain oscillates
+  pub base_height: f32,
+  /// Maximum deviation (in voxels) of the terrain from `base_height`
+  pub amplitude: f32,
+  /// Air below this height is filled with water
+  pub sea_level: f32,
+  /// Frequency of the temperature and humidity noise used to select biomes
+  pub biome_frequency: f32,
+  /// Frequency of the 3D noise added to the height gradient in density mode
+  pub density_frequency: f32,
+  /// How many voxels the 3D noise can move the surface up or down
+  pub density_strength: f32,
+  pub cave_frequency: f32,
+  /// Caves are carved where the absolute cave noise value is below this
+  /// threshold, larger values give wider tunnels
+  pub cave_threshold: f32,
+}
+
+impl Default for WorldGenConfig {
+  fn default() -> Self {
+    Self {
+      terrain: TerrainKind::Heightmap,
+      seed: DEFAULT_LEVEL_SEED,
+      octaves: 5,
+      frequency: 0.02 / (WORLD_RESOLUTION as f32),
+      lacunarity: 0.5,
+      gain: 2.0,
+      base_height: DEFAULT_GROUND_LEVEL,
+      amplitude: DEFAULT_NOISE_GROUND_MAX_OFFSET,
+      sea_level: DEFAULT_SEA_LEVEL,
+      biome_frequency: 0.002 / (WORLD_RESOLUTION as f32),
+      density_frequency: 0.03 / (WORLD_RESOLUTION as f32),
+      density_strength: 24.0,
+      cave_frequency: 0.04 / (WORLD_RESOLUTION as f32),
+      cave_threshold: 0.08,
+    }
+  }
+}
+
+impl WorldGenConfig {
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+    let file = File::open(path)?;
+    Ok(ron::de::from_reader(file)?)
+  }
+
+  /// Loads the config from `path`, falling back to defaults when the file
+  /// doesn't exist or can't be parsed.
+  pub fn from_file_or_default<P: AsRef<Path>>(path: P) -> Self {
+    match Self::from_file(&path) {
+      Ok(config) => config,
+      Err(ConfigError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+      Err(err) => {
+        warn!(
+          "Failed to load world gen config from {:?}: {:?}",
+          path.as_ref(),
+          err
+        );
+        Self::default()
+      }
+    }
+  }
+}
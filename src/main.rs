@@ -6,7 +6,7 @@ mod world;
 use bevy::app::{Events, ManualEventReader};
 use bevy::prelude::*;
 
-use crate::config::PlayerConfig;
+use crate::config::{PlayerConfig, WorldGenConfig, WORLD_GEN_CONFIG_PATH};
 use crate::player::{
   CursorGrabStatus, Player, PlayerCamera, PlayerController, PlayerControllerPlugin,
 };
@@ -47,6 +47,7 @@ struct State {
 fn main() {
   App::new()
     .insert_resource(PlayerConfig::default())
+    .insert_resource(WorldGenConfig::from_file_or_default(WORLD_GEN_CONFIG_PATH))
     .insert_resource(WindowDescriptor {
       title: WINDOW_TITLE.to_string(),
       vsync: true,
@@ -1,5 +1,8 @@
 use crate::config::{MovementSettings, PlayerConfig};
-use crate::world::WORLD_RESOLUTION;
+use crate::world::{
+  BlockId, BlockRegistry, Chunk, ChunkLoader, ChunkState, RaycastHit, RemeshSectionRequest, Voxel,
+  VoxelWorld, WORLD_RESOLUTION,
+};
 use bevy::{
   app::{EventReader, ManualEventReader, Plugin},
   asset::Assets,
@@ -8,11 +11,11 @@ use bevy::{
     mouse::{MouseButtonInput, MouseMotion},
     ElementState, Input,
   },
-  math::{const_vec3, Quat, Vec3},
+  math::{const_vec3, IVec3, Quat, Vec3},
   prelude::{
-    shape, App, BuildChildren, Bundle, Children, Color, Commands, Component, GlobalTransform,
-    IntoSystem, Mesh, MouseButton, PbrBundle, PerspectiveCameraBundle, Query, Res, ResMut,
-    StandardMaterial, Time, Transform, Vec2, Windows, With, Without,
+    shape, App, BuildChildren, Bundle, Children, Color, Commands, Component, EventWriter,
+    GlobalTransform, IntoSystem, Mesh, MouseButton, PbrBundle, PerspectiveCameraBundle, Query, Res,
+    ResMut, StandardMaterial, Time, Transform, Vec2, Windows, With, Without,
   },
   render::camera::PerspectiveProjection,
   utils::HashMap,
@@ -32,16 +35,22 @@ enum Action {
   Speedup,
 
   ToggleCursorGrab,
+
+  BreakBlock,
+  PlaceBlock,
+  PickBlock,
 }
 
+/// How far beyond the camera distance blocks can be reached
+const BLOCK_REACH: f32 = 8.0 * WORLD_RESOLUTION as f32;
+
 #[derive(Default)]
 pub struct CursorGrabStatus(bool);
 
 #[derive(Hash, Eq, PartialEq)]
 enum UserInput {
   Keyboard(KeyCode),
-  // TODO
-  // Mouse(MouseButton),
+  Mouse(MouseButton),
 }
 
 struct KeyBinds(HashMap<UserInput, Action>);
@@ -68,6 +77,9 @@ impl Default for KeyBinds {
       UserInput::Keyboard(KeyCode::Escape),
       Action::ToggleCursorGrab,
     );
+    binds.insert(UserInput::Mouse(MouseButton::Left), Action::BreakBlock);
+    binds.insert(UserInput::Mouse(MouseButton::Right), Action::PlaceBlock);
+    binds.insert(UserInput::Mouse(MouseButton::Middle), Action::PickBlock);
 
     KeyBinds(binds)
   }
@@ -95,6 +107,14 @@ fn handle_user_input(
       }
     }
   }
+  for mouse_event in mouse_events.iter() {
+    if let Some(action) = keybinds.get(&UserInput::Mouse(mouse_event.button)) {
+      match mouse_event.state {
+        ElementState::Pressed => actions.press(*action),
+        ElementState::Released => actions.release(*action),
+      }
+    }
+  }
 }
 
 fn handle_player_input(
@@ -123,6 +143,7 @@ fn handle_player_input(
             controller.cursor_grab = !controller.cursor_grab
           }
         }
+        Action::BreakBlock | Action::PlaceBlock | Action::PickBlock => {}
       }
     }
 
@@ -195,6 +216,78 @@ fn handle_mouse_move(
   }
 }
 
+/// Casts a ray from the player camera to find the solid block the player
+/// looks at. Non-solid blocks such as water are looked through.
+fn update_block_target(
+  player_config: Res<PlayerConfig>,
+  world: Res<VoxelWorld>,
+  registry: Res<BlockRegistry>,
+  chunks: Query<(&Chunk, &ChunkState)>,
+  cameras: Query<&GlobalTransform, With<PlayerCamera>>,
+  mut controllers: Query<&mut PlayerController>,
+) {
+  let camera = match cameras.iter().next() {
+    Some(camera) => camera,
+    None => return,
+  };
+
+  for mut controller in controllers.iter_mut() {
+    controller.target = world
+      .raycast(
+        &chunks,
+        camera.translation,
+        camera.rotation.mul_vec3(-Vec3::Z),
+        player_config.camera_distance + BLOCK_REACH,
+        |voxel| registry.get(voxel.block).solid,
+      )
+      .and_then(|hit| {
+        let voxel = world.get_voxel(&chunks, hit.voxel)?;
+        Some(BlockTarget { hit, voxel })
+      });
+  }
+}
+
+/// Breaks, places or picks the targeted block
+fn interact_with_blocks(
+  actions: Res<Input<Action>>,
+  world: Res<VoxelWorld>,
+  registry: Res<BlockRegistry>,
+  mut controllers: Query<&mut PlayerController>,
+  mut chunks: Query<(&mut Chunk, &ChunkState)>,
+  mut remesh_requests: EventWriter<RemeshSectionRequest>,
+) {
+  for mut controller in controllers.iter_mut() {
+    let target = match controller.target {
+      Some(target) if controller.cursor_grab => target,
+      _ => continue,
+    };
+
+    if actions.just_pressed(Action::BreakBlock) {
+      // Negative hardness marks unbreakable blocks
+      if registry.get(target.voxel.block).hardness >= 0.0 {
+        world.set_voxel(
+          &mut chunks,
+          &mut remesh_requests,
+          target.hit.voxel,
+          Voxel::default(),
+        );
+      }
+    } else if actions.just_pressed(Action::PlaceBlock) {
+      // Zero normal means the camera is inside the targeted block
+      if target.hit.normal != IVec3::ZERO {
+        world.set_voxel(
+          &mut chunks,
+          &mut remesh_requests,
+          target.hit.voxel + target.hit.normal,
+          Voxel::new(controller.selected_block),
+        );
+      }
+    } else if actions.just_pressed(Action::PickBlock) {
+      controller.selected_block = target.voxel.block;
+    }
+  }
+}
+
 #[derive(Default)]
 struct PlayerInputState {
   reader_motion: ManualEventReader<MouseMotion>,
@@ -270,7 +363,11 @@ fn setup_player_camera(
       });
     })
     .insert(Player::default())
-    .insert(PlayerController::default());
+    .insert(PlayerController::default())
+    .insert(ChunkLoader {
+      radius: player_config.chunk_render_distance,
+      unload_radius: player_config.chunk_unload_distance,
+    });
 }
 
 #[derive(Debug, Default, Component, Bundle)]
@@ -279,11 +376,21 @@ pub struct Player {
   global_transform: GlobalTransform,
 }
 
+/// Block the player is looking at
+#[derive(Debug, Clone, Copy)]
+pub struct BlockTarget {
+  pub hit: RaycastHit,
+  pub voxel: Voxel,
+}
+
 #[derive(Component, Debug)]
 pub struct PlayerController {
   pub cursor_grab: bool,
   pub pitch: f32,
   pub yaw: f32,
+  /// Block placed by `Action::PlaceBlock`
+  pub selected_block: BlockId,
+  pub target: Option<BlockTarget>,
 }
 
 impl Default for PlayerController {
@@ -292,6 +399,8 @@ impl Default for PlayerController {
       pitch: -0.8,
       yaw: 0.0,
       cursor_grab: false,
+      selected_block: BlockId::DIRT,
+      target: None,
     }
   }
 }
@@ -300,6 +409,9 @@ pub struct PlayerControllerPlugin;
 
 impl Plugin for PlayerControllerPlugin {
   fn build(&self, app: &mut App) {
+    const HANDLE_USER_INPUT_LABEL: &'static str = "handle_user_input";
+    const UPDATE_BLOCK_TARGET_LABEL: &'static str = "update_block_target";
+
     app
       .init_resource::<PlayerController>()
       .init_resource::<KeyBinds>()
@@ -307,10 +419,21 @@ impl Plugin for PlayerControllerPlugin {
       .init_resource::<PlayerInputState>()
       .init_resource::<CursorGrabStatus>()
       .init_resource::<MovementSettings>()
-      .add_system(handle_user_input.system())
+      .add_system(handle_user_input.system().label(HANDLE_USER_INPUT_LABEL))
       .add_system(handle_player_input.system())
       .add_system(handle_mouse_move.system())
       .add_system(update_cursor_grab.system())
+      .add_system(
+        update_block_target
+          .system()
+          .label(UPDATE_BLOCK_TARGET_LABEL),
+      )
+      .add_system(
+        interact_with_blocks
+          .system()
+          .after(HANDLE_USER_INPUT_LABEL)
+          .after(UPDATE_BLOCK_TARGET_LABEL),
+      )
       .add_startup_system(setup_player_camera.system());
   }
 }
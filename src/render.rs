@@ -1,10 +1,17 @@
 use crate::config::PlayerConfig;
-use crate::world::{chunk_extent, Chunk, ChunkReadyEvent, Voxel, WorldUpdateStage};
+use crate::player::Player;
+use crate::world::{
+  neighbour_offsets, section_extent, Block, BlockId, BlockRegistry, Chunk, ChunkSection,
+  ChunkState, ChunkStateChanged, ChunkVoxels, ChunkWork, ChunkWorkScheduler, RemeshSectionRequest,
+  Voxel, VoxelWorld, WorldPos, WorldUpdateStage, SECTIONS_PER_CHUNK, SECTION_HEIGHT,
+};
 use bevy::pbr::render_graph::PBR_PIPELINE_HANDLE;
 use bevy::render::mesh::Indices;
 use bevy::render::pipeline::PrimitiveTopology;
 use bevy::render::render_graph::base::MainPass;
 use bevy::render::shader::ShaderStages;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::HashSet;
 use bevy::{
   prelude::*,
   reflect::TypeUuid,
@@ -18,8 +25,12 @@ use building_blocks::{
   },
   prelude::*,
 };
+use futures_lite::future;
 use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
 
+/// Queues a `ChunkSection` entity for meshing
 struct ChunkMeshingEvent(Entity);
 
 pub const TERRAIN_PIPELINE_HANDLE: HandleUntyped =
@@ -36,12 +47,12 @@ pub struct ChunkRenderBundle {
 }
 
 fn attach_chunk_render_bundle(
-  chunks: Query<Entity, Added<Chunk>>,
+  sections: Query<Entity, Added<ChunkSection>>,
   mut commands: Commands,
   mut mats: ResMut<Assets<StandardMaterial>>,
   mut meshes: ResMut<Assets<Mesh>>,
 ) {
-  for ent in chunks.iter() {
+  for ent in sections.iter() {
     commands.entity(ent).insert_bundle(ChunkRenderBundle {
       mesh: meshes.add(Mesh::new(PrimitiveTopology::TriangleList)),
       material: mats.add(Default::default()),
@@ -59,55 +70,443 @@ fn attach_chunk_render_bundle(
   }
 }
 
+/// Meshing in progress on the `AsyncComputeTaskPool`
+#[derive(Component)]
+struct ChunkMeshingTask(Task<(ChunkMesh, Duration)>);
+
+/// Level of detail a chunk is meshed with. Level `n` merges `2^n` voxels
+/// along every axis into a single cell before meshing.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLod(pub u8);
+
+impl ChunkLod {
+  /// Edge length of a downsampled cell, in voxels
+  #[inline]
+  pub fn scale(&self) -> i32 {
+    1 << self.0
+  }
+
+  fn for_distance(distance: f32, config: &PlayerConfig) -> Self {
+    let level = config
+      .lod_distances
+      .iter()
+      .filter(|lod_distance| distance >= **lod_distance as f32)
+      .count();
+    ChunkLod(level as u8)
+  }
+}
+
+/// Fine voxels covered by cell `c` of a downsampled axis. Padding cells
+/// cover `scale` voxels of padding, just like the neighbour's own cell.
+fn fine_range(c: i32, scale: i32) -> RangeInclusive<i32> {
+  c * scale..=c * scale + scale - 1
+}
+
+/// Copies section `section_y` of `voxels` with `padding` voxels around it
+/// into a dense array in section-local coordinates.
+///
+/// Downsampled padding cells have to cover the same voxels as the cells of
+/// the neighbouring section, so padding thicker than the single layer stored
+/// in the chunk is read from the rest of the chunk column vertically and from
+/// the loaded `neighbours` (ordered as `neighbour_offsets`) horizontally.
+/// Without a neighbour, the chunk's own padding layer, which is empty while
+/// the neighbour isn't loaded, is repeated. Corners are left empty as
+/// they don't affect any face.
+fn section_snapshot(
+  voxels: &ChunkVoxels,
+  neighbours: &[Option<&ChunkVoxels>; 4],
+  section_y: i32,
+  padding: i32,
+) -> Array3x1<Voxel> {
+  let [size_x, _, size_z] = section_extent().shape.0;
+  let min_y = section_y * SECTION_HEIGHT;
+  let max_y = SECTIONS_PER_CHUNK * SECTION_HEIGHT;
+
+  Array3x1::fill_with(section_extent().padded(padding), |p: Point3i| {
+    let [x, y, z] = p.0;
+    let y = y + min_y;
+    if y < -1 || y > max_y {
+      return Voxel::default();
+    }
+
+    let outside_x = x < 0 || x >= size_x;
+    let outside_z = z < 0 || z >= size_z;
+    let side = match (outside_x, outside_z) {
+      (false, false) => return voxels.get(PointN([x, y, z])),
+      (true, true) => return Voxel::default(),
+      (true, false) => (x >= size_x) as usize,
+      (false, true) => 2 + (z >= size_z) as usize,
+    };
+
+    match neighbours[side] {
+      Some(neighbour) => {
+        let offset = neighbour_offsets()[side];
+        neighbour.get(PointN([x - offset.x * size_x, y, z - offset.y * size_z]))
+      }
+      None => voxels.get(PointN([x.clamp(-1, size_x), y, z.clamp(-1, size_z)])),
+    }
+  })
+}
+
+/// Most common non-empty block of a downsampled cell, or air if less than
+/// half of the cell is filled
+fn downsample_cell(
+  voxels: &Array3x1<Voxel>,
+  registry: &BlockRegistry,
+  cell: Point3i,
+  scale: i32,
+) -> Voxel {
+  let mut counts: Vec<(Voxel, u32)> = Vec::new();
+  let (mut filled, mut total) = (0, 0);
+
+  for x in fine_range(cell.x(), scale) {
+    for y in fine_range(cell.y(), scale) {
+      for z in fine_range(cell.z(), scale) {
+        total += 1;
+        let voxel = voxels.get(PointN([x, y, z]));
+        if registry.get(voxel.block).is_empty() {
+          continue;
+        }
+
+        filled += 1;
+        match counts.iter_mut().find(|(v, _)| *v == voxel) {
+          Some((_, count)) => *count += 1,
+          None => counts.push((voxel, 1)),
+        }
+      }
+    }
+  }
+
+  if filled * 2 < total {
+    return Voxel::default();
+  }
+  counts
+    .into_iter()
+    .max_by_key(|(_, count)| *count)
+    .map(|(voxel, _)| voxel)
+    .unwrap_or_default()
+}
+
+/// Meshes a section snapshot padded by `lod.scale()` voxels at the given
+/// LOD. Padding on the sides
+/// flagged in `seams` (neighbours with another LOD) is treated as empty, so
+/// the border faces are kept and close the gaps between the two meshes.
+fn build_section_mesh(
+  voxels: &Array3x1<Voxel>,
+  registry: &BlockRegistry,
+  lod: ChunkLod,
+  seams: [bool; 4],
+) -> ChunkMesh {
+  let scale = lod.scale();
+  let [size_x, size_y, size_z] = section_extent().shape.0;
+  let shape = PointN([size_x / scale, size_y / scale, size_z / scale]);
+  let extent = Extent3i::from_min_and_shape(PointN([0; 3]), shape);
+
+  let voxels = Array3x1::fill_with(extent.padded(1), |p: Point3i| {
+    let is_seam = (seams[0] && p.x() < 0)
+      || (seams[1] && p.x() >= shape.x())
+      || (seams[2] && p.z() < 0)
+      || (seams[3] && p.z() >= shape.z());
+
+    let voxel = if is_seam {
+      Voxel::default()
+    } else if scale == 1 {
+      voxels.get(p)
+    } else {
+      downsample_cell(voxels, registry, p, scale)
+    };
+    RegistryVoxel { voxel, registry }
+  });
+
+  let mut greedy_buffer =
+    GreedyQuadsBuffer::new(extent.padded(1), RIGHT_HANDED_Y_UP_CONFIG.quad_groups());
+  greedy_quads(&voxels, &extent.padded(1), &mut greedy_buffer);
+
+  let mut chunk_mesh = ChunkMesh::default();
+
+  for group in greedy_buffer.quad_groups.iter() {
+    for quad in group.quads.iter() {
+      let block = voxels.get(quad.minimum).block();
+      chunk_mesh.add_quad_to_mesh(&group.face, quad, block.color, scale as f32);
+    }
+  }
+
+  chunk_mesh
+}
+
+/// Spawns meshing tasks for queued sections. Each task works on a dense
+/// snapshot of the section and its padding, so the chunk can be modified
+/// while it is being meshed. Sections made of empty blocks only are hidden
+/// without meshing. Only as many sections as the `ChunkWorkScheduler` allows
+/// are meshed per frame.
 fn mesh_chunks_async(
-  player_config: Res<PlayerConfig>,
-  mut chunks: Query<(&Chunk, &mut Visible, &Handle<Mesh>)>,
+  mut commands: Commands,
+  task_pool: Res<AsyncComputeTaskPool>,
+  registry: Res<BlockRegistry>,
+  world: Res<VoxelWorld>,
+  mut chunks: Query<(&Chunk, &mut ChunkState)>,
+  neighbour_chunks: Query<&Chunk>,
+  lods: Query<&ChunkLod>,
+  mut sections: Query<(&ChunkSection, &Parent, &mut Visible)>,
   mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
+  mut scheduler: ResMut<ChunkWorkScheduler>,
+  mut state_events: EventWriter<ChunkStateChanged>,
+) {
+  while scheduler.has_budget(ChunkWork::Meshing) {
+    let meshing_event = match meshing_events.pop_back() {
+      Some(meshing_event) => meshing_event,
+      None => break,
+    };
+
+    let (section, parent, mut visibility) = match sections.get_mut(meshing_event.0) {
+      Ok(section) => section,
+      Err(_) => continue,
+    };
+    let (chunk, mut state) = match chunks.get_mut(parent.0) {
+      Ok(chunk) => chunk,
+      Err(_) => continue,
+    };
+
+    // Sections of chunks without voxels are queued again once the chunk is
+    // generated
+    match *state {
+      ChunkState::Generated | ChunkState::Ready => {
+        state.transition(ChunkState::Meshing, parent.0, chunk.pos, &mut state_events);
+      }
+      ChunkState::Meshing => {}
+      _ => continue,
+    }
+    let lod = lods.get(parent.0).ok().copied().unwrap_or_default();
+
+    let section_y = section.section_y();
+    if let Some(voxel) = chunk.block_data.uniform_section(section_y) {
+      if registry.get(voxel.block).is_empty() {
+        visibility.is_visible = false;
+        commands
+          .entity(meshing_event.0)
+          .remove::<ChunkMeshingTask>();
+        continue;
+      }
+    }
+
+    // Chunks still loading hold empty placeholder voxels, which matches
+    // the padding towards a neighbour that isn't loaded
+    let mut seams = [false; 4];
+    let mut neighbours = [None; 4];
+    for (i, offset) in neighbour_offsets().iter().enumerate() {
+      let neighbour = match world.loaded_chunks.get(&(chunk.pos + *offset)) {
+        Some(neighbour) => *neighbour,
+        None => continue,
+      };
+      seams[i] = lods
+        .get(neighbour)
+        .map_or(false, |neighbour_lod| *neighbour_lod != lod);
+      neighbours[i] = neighbour_chunks
+        .get(neighbour)
+        .ok()
+        .map(|neighbour| &neighbour.block_data);
+    }
+
+    let voxels = section_snapshot(&chunk.block_data, &neighbours, section_y, lod.scale());
+    let registry = registry.clone();
+    let task = task_pool.spawn(async move {
+      let start = Instant::now();
+      let mesh = build_section_mesh(&voxels, &registry, lod, seams);
+      (mesh, start.elapsed())
+    });
+
+    // Replaces (and cancels) a task started for an outdated snapshot
+    commands
+      .entity(meshing_event.0)
+      .insert(ChunkMeshingTask(task));
+    scheduler.spend(ChunkWork::Meshing);
+  }
+
+  scheduler.set_queue_len(ChunkWork::Meshing, meshing_events.len());
+}
+
+fn apply_chunk_meshes(
+  mut commands: Commands,
+  mut chunks: Query<(Entity, &mut Visible, &Handle<Mesh>, &mut ChunkMeshingTask)>,
   mut meshes: ResMut<Assets<Mesh>>,
+  mut scheduler: ResMut<ChunkWorkScheduler>,
+) {
+  for (entity, mut visibility, mesh_handle, mut task) in chunks.iter_mut() {
+    if let Some((chunk_mesh, duration)) = future::block_on(future::poll_once(&mut task.0)) {
+      commands.entity(entity).remove::<ChunkMeshingTask>();
+      scheduler.record(ChunkWork::Meshing, duration);
+
+      let mesh = meshes.get_mut(mesh_handle).unwrap();
+      let ChunkMesh {
+        positions,
+        normals,
+        indices,
+        colors,
+        uv,
+      } = chunk_mesh;
+
+      mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+      mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+      mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uv);
+      mesh.set_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+      mesh.set_indices(Some(Indices::U32(indices)));
+
+      visibility.is_visible = true;
+    }
+  }
+}
+
+/// Marks chunks as `Ready` once none of their sections is queued for meshing
+/// or being meshed. Runs after the commands inserting and removing meshing
+/// tasks have been applied.
+fn finish_chunk_meshing(
+  meshing_events: Res<VecDeque<ChunkMeshingEvent>>,
+  sections: Query<(&Parent, Option<&ChunkMeshingTask>), With<ChunkSection>>,
+  mut chunks: Query<(Entity, &Chunk, &mut ChunkState)>,
+  mut state_events: EventWriter<ChunkStateChanged>,
+) {
+  let mut pending: HashSet<Entity> = HashSet::default();
+  for (parent, task) in sections.iter() {
+    if task.is_some() {
+      pending.insert(parent.0);
+    }
+  }
+  for meshing_event in meshing_events.iter() {
+    if let Ok((parent, _)) = sections.get(meshing_event.0) {
+      pending.insert(parent.0);
+    }
+  }
+
+  for (entity, chunk, mut state) in chunks.iter_mut() {
+    if *state == ChunkState::Meshing && !pending.contains(&entity) {
+      state.transition(ChunkState::Ready, entity, chunk.pos, &mut state_events);
+    }
+  }
+}
+
+/// Drops queued meshing requests of sections despawned with their chunk.
+/// Meshing tasks in flight are cancelled by despawning the sections.
+fn drop_unloaded_sections(
+  mut state_events: EventReader<ChunkStateChanged>,
+  sections: Query<Entity, With<ChunkSection>>,
+  mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
+) {
+  let unloaded = state_events
+    .iter()
+    .filter(|state_event| state_event.to == ChunkState::Unloading)
+    .count();
+  if unloaded > 0 {
+    meshing_events.retain(|meshing_event| sections.get(meshing_event.0).is_ok());
+  }
+}
+
+/// Remeshes all sections of loaded chunks when block definitions change,
+/// e.g. on hot reload of the block definitions asset
+fn remesh_on_block_registry_change(
+  registry: Res<BlockRegistry>,
+  chunks: Query<&ChunkState, With<Chunk>>,
+  sections: Query<(Entity, &Parent), With<ChunkSection>>,
+  mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
+) {
+  if !registry.is_changed() {
+    return;
+  }
+
+  for (entity, parent) in sections.iter() {
+    if let Ok(state) = chunks.get(parent.0) {
+      if state.has_voxels() {
+        meshing_events.push_front(ChunkMeshingEvent(entity));
+      }
+    }
+  }
+}
+
+fn queue_chunk_sections(
+  world: &VoxelWorld,
+  chunk_pos: IVec2,
+  meshing_events: &mut VecDeque<ChunkMeshingEvent>,
+) {
+  for section_y in 0..SECTIONS_PER_CHUNK {
+    let pos = IVec3::new(chunk_pos.x, section_y, chunk_pos.y);
+    if let Some(section) = world.loaded_sections.get(&pos) {
+      meshing_events.push_front(ChunkMeshingEvent(*section));
+    }
+  }
+}
+
+fn handle_generated_chunks(
+  world: Res<VoxelWorld>,
+  mut state_events: EventReader<ChunkStateChanged>,
+  mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
+) {
+  for state_event in state_events.iter() {
+    if state_event.to == ChunkState::Generated {
+      queue_chunk_sections(&world, state_event.pos, &mut meshing_events);
+    }
+  }
+}
+
+/// Picks the LOD of every chunk by its distance to the player. Loaded chunks
+/// whose LOD changed are remeshed together with their neighbours, as the
+/// seams between them change as well.
+fn update_chunk_lods(
+  mut commands: Commands,
+  player_config: Res<PlayerConfig>,
+  world: Res<VoxelWorld>,
+  players: Query<&Transform, With<Player>>,
+  mut chunks: Query<(Entity, &Chunk, &ChunkState, Option<&mut ChunkLod>)>,
+  mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
 ) {
-  for _ in 0..(player_config.chunk_render_distance / 2) {
-    if let Some(meshing_event) = meshing_events.pop_back() {
-      if let Ok((chunk, mut visibility, mesh_handle)) = chunks.get_mut(meshing_event.0) {
-        let mesh = meshes.get_mut(mesh_handle).unwrap();
-        let extent = chunk_extent();
-        let mut greedy_buffer =
-          GreedyQuadsBuffer::new(extent.padded(1), RIGHT_HANDED_Y_UP_CONFIG.quad_groups());
-        greedy_quads(&chunk.block_data, &extent.padded(1), &mut greedy_buffer);
-
-        let mut chunk_mesh = ChunkMesh::default();
-
-        for group in greedy_buffer.quad_groups.iter() {
-          for quad in group.quads.iter() {
-            chunk_mesh.add_quad_to_mesh(&group.face, quad, &chunk.block_data.get(quad.minimum));
-          }
+  let player_chunk = match players.iter().next() {
+    Some(transform) => WorldPos(transform.translation).chunk().0,
+    None => return,
+  };
+
+  let mut changed = Vec::new();
+  let mut with_voxels = HashSet::default();
+  for (entity, chunk, state, lod) in chunks.iter_mut() {
+    if state.has_voxels() {
+      with_voxels.insert(chunk.pos);
+    }
+
+    let delta = chunk.pos - player_chunk;
+    let distance = ((delta.x.pow(2) + delta.y.pow(2)) as f32).sqrt();
+    let new_lod = ChunkLod::for_distance(distance, &player_config);
+
+    match lod {
+      Some(mut lod) if *lod != new_lod => {
+        *lod = new_lod;
+        if state.has_voxels() {
+          changed.push(chunk.pos);
         }
+      }
+      Some(_) => {}
+      None => {
+        commands.entity(entity).insert(new_lod);
+      }
+    }
+  }
 
-        let ChunkMesh {
-          positions,
-          normals,
-          indices,
-          colors,
-          uv,
-        } = chunk_mesh;
-
-        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-        mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-        mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uv);
-        mesh.set_attribute(Mesh::ATTRIBUTE_COLOR, colors);
-        mesh.set_indices(Some(Indices::U32(indices)));
-
-        visibility.is_visible = true;
+  for chunk_pos in changed {
+    queue_chunk_sections(&world, chunk_pos, &mut meshing_events);
+    for offset in neighbour_offsets().iter() {
+      let neighbour_pos = chunk_pos + *offset;
+      if with_voxels.contains(&neighbour_pos) {
+        queue_chunk_sections(&world, neighbour_pos, &mut meshing_events);
       }
     }
   }
 }
 
-fn handle_chunk_ready_events(
-  mut ready_events: EventReader<ChunkReadyEvent>,
+fn handle_remesh_section_requests(
+  world: Res<VoxelWorld>,
+  mut remesh_requests: EventReader<RemeshSectionRequest>,
   mut meshing_events: ResMut<VecDeque<ChunkMeshingEvent>>,
 ) {
-  for ready_event in ready_events.iter() {
-    meshing_events.push_front(ChunkMeshingEvent(ready_event.1));
+  for request in remesh_requests.iter() {
+    if let Some(section) = world.loaded_sections.get(&request.0) {
+      meshing_events.push_front(ChunkMeshingEvent(*section));
+    }
   }
 }
 
@@ -125,6 +524,10 @@ pub struct WorldRenderPlugin;
 
 impl Plugin for WorldRenderPlugin {
   fn build(&self, app: &mut App) {
+    // Everything queueing sections runs before they are dispatched, so the
+    // scheduler sees the full queue when planning the next frame
+    const MESH_CHUNKS_LABEL: &'static str = "mesh_chunks_async";
+
     app
       .add_event::<ChunkMeshingEvent>()
       .init_resource::<VecDeque<ChunkMeshingEvent>>()
@@ -133,28 +536,57 @@ impl Plugin for WorldRenderPlugin {
         WorldUpdateStage::PostUpdate,
         attach_chunk_render_bundle.system(),
       )
-      .add_system(handle_chunk_ready_events.system())
-      .add_system(mesh_chunks_async.system());
+      .add_system(handle_generated_chunks.system().before(MESH_CHUNKS_LABEL))
+      .add_system(drop_unloaded_sections.system().before(MESH_CHUNKS_LABEL))
+      .add_system(
+        handle_remesh_section_requests
+          .system()
+          .before(MESH_CHUNKS_LABEL),
+      )
+      .add_system(update_chunk_lods.system().before(MESH_CHUNKS_LABEL))
+      .add_system(
+        remesh_on_block_registry_change
+          .system()
+          .before(MESH_CHUNKS_LABEL),
+      )
+      .add_system(mesh_chunks_async.system().label(MESH_CHUNKS_LABEL))
+      .add_system(apply_chunk_meshes.system())
+      .add_system_to_stage(CoreStage::PostUpdate, finish_chunk_meshing.system());
   }
 }
 
-impl MergeVoxel for Voxel {
-  type VoxelValue = u8;
+/// Voxel paired with the block registry, so the mesher can look up block
+/// properties
+#[derive(Clone, Copy)]
+struct RegistryVoxel<'a> {
+  voxel: Voxel,
+  registry: &'a BlockRegistry,
+}
+
+impl RegistryVoxel<'_> {
+  #[inline]
+  fn block(&self) -> &Block {
+    self.registry.get(self.voxel.block)
+  }
+}
+
+impl MergeVoxel for RegistryVoxel<'_> {
+  type VoxelValue = BlockId;
 
   fn voxel_merge_value(&self) -> Self::VoxelValue {
-    self.attributes[0]
+    self.voxel.block
   }
 }
 
-impl IsOpaque for Voxel {
+impl IsOpaque for RegistryVoxel<'_> {
   fn is_opaque(&self) -> bool {
-    true
+    self.block().opaque
   }
 }
 
-impl IsEmpty for Voxel {
+impl IsEmpty for RegistryVoxel<'_> {
   fn is_empty(&self) -> bool {
-    self.attributes[3] == 0
+    self.block().is_empty()
   }
 }
 
@@ -168,19 +600,98 @@ struct ChunkMesh {
 }
 
 impl ChunkMesh {
-  fn add_quad_to_mesh(&mut self, face: &OrientedCubeFace, quad: &UnorientedQuad, voxel: &Voxel) {
+  fn add_quad_to_mesh(
+    &mut self,
+    face: &OrientedCubeFace,
+    quad: &UnorientedQuad,
+    color: [u8; 4],
+    voxel_size: f32,
+  ) {
     let start_index = self.positions.len() as u32;
 
     self
       .positions
-      .extend_from_slice(&face.quad_mesh_positions(quad, 1.0));
+      .extend_from_slice(&face.quad_mesh_positions(quad, voxel_size));
     self.normals.extend_from_slice(&face.quad_mesh_normals());
     self
       .uv
       .extend_from_slice(&face.tex_coords(RIGHT_HANDED_Y_UP_CONFIG.u_flip_face, false, quad));
-    self.colors.extend_from_slice(&[voxel.attributes; 4]);
+    self.colors.extend_from_slice(&[color; 4]);
     self
       .indices
       .extend_from_slice(&face.quad_mesh_indices(start_index));
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DIRT: Voxel = Voxel::new(BlockId::DIRT);
+
+  /// Fills three of the four voxels of a 1×2×2 layer at `x`, starting at
+  /// `(y, z)`
+  fn fill_layer(voxels: &mut ChunkVoxels, x: i32, y: i32, z: i32) {
+    voxels.set(PointN([x, y, z]), DIRT);
+    voxels.set(PointN([x, y + 1, z]), DIRT);
+    voxels.set(PointN([x, y, z + 1]), DIRT);
+  }
+
+  #[test]
+  fn padding_cells_match_horizontal_neighbour() {
+    let registry = BlockRegistry::default();
+    let scale = ChunkLod(1).scale();
+    let [size_x, _, _] = section_extent().shape.0;
+
+    // The neighbour at +x has a mostly filled layer next to the border and
+    // an empty layer behind it, so its own cell is air
+    let chunk = ChunkVoxels::new(Voxel::default());
+    let mut neighbour = ChunkVoxels::new(Voxel::default());
+    fill_layer(&mut neighbour, 0, 4, 4);
+
+    let snapshot = section_snapshot(&chunk, &[None, Some(&neighbour), None, None], 0, scale);
+    let neighbour_snapshot =
+      section_snapshot(&neighbour, &[Some(&chunk), None, None, None], 0, scale);
+
+    let padding_cell = downsample_cell(&snapshot, &registry, PointN([size_x / scale, 2, 2]), scale);
+    let own_cell = downsample_cell(&neighbour_snapshot, &registry, PointN([0, 2, 2]), scale);
+    assert_eq!(padding_cell, own_cell);
+    assert_eq!(own_cell, Voxel::default());
+  }
+
+  #[test]
+  fn padding_cells_match_vertical_neighbour() {
+    let registry = BlockRegistry::default();
+    let scale = ChunkLod(1).scale();
+
+    // Bottom layer of section 1 is mostly filled, the layer above is empty
+    let mut voxels = ChunkVoxels::new(Voxel::default());
+    voxels.set(PointN([2, SECTION_HEIGHT, 2]), DIRT);
+    voxels.set(PointN([3, SECTION_HEIGHT, 2]), DIRT);
+    voxels.set(PointN([2, SECTION_HEIGHT, 3]), DIRT);
+
+    let neighbours = [None; 4];
+    let below = section_snapshot(&voxels, &neighbours, 0, scale);
+    let above = section_snapshot(&voxels, &neighbours, 1, scale);
+
+    let padding_cell = downsample_cell(
+      &below,
+      &registry,
+      PointN([1, SECTION_HEIGHT / scale, 1]),
+      scale,
+    );
+    let own_cell = downsample_cell(&above, &registry, PointN([1, 0, 1]), scale);
+    assert_eq!(padding_cell, own_cell);
+  }
+
+  #[test]
+  fn missing_neighbour_repeats_padding_layer() {
+    let mut chunk = ChunkVoxels::new(Voxel::default());
+    chunk.set(PointN([-1, 5, 5]), DIRT);
+
+    let snapshot = section_snapshot(&chunk, &[None; 4], 0, 2);
+    assert_eq!(snapshot.get(PointN([-1, 5, 5])), DIRT);
+    assert_eq!(snapshot.get(PointN([-2, 5, 5])), DIRT);
+    assert_eq!(snapshot.get(PointN([-2, 5, -2])), Voxel::default());
+  }
+}
@@ -0,0 +1,158 @@
+use crate::config::WorldGenConfig;
+use crate::world::{BlockId, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
+use simdnoise::NoiseBuilder;
+
+/// Controls how quickly biome parameters fade into each other.
+/// Higher values give narrower transition zones.
+const BIOME_BLEND_SHARPNESS: f32 = 12.0;
+
+const TEMPERATURE_SEED_OFFSET: i32 = 1;
+const HUMIDITY_SEED_OFFSET: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Biome {
+  Plains,
+  Desert,
+  Mountains,
+  Tundra,
+  Ocean,
+}
+
+pub struct BiomeParams {
+  /// Position of the biome in the (temperature, humidity) climate space,
+  /// both in range [-1.0, 1.0]
+  pub climate: (f32, f32),
+  /// Offset from `WorldGenConfig::base_height`
+  pub height_offset: f32,
+  /// Multiplier of `WorldGenConfig::amplitude`
+  pub amplitude_scale: f32,
+  pub surface: Voxel,
+  pub subsurface: Voxel,
+}
+
+impl Biome {
+  pub const ALL: [Biome; 5] = [
+    Biome::Plains,
+    Biome::Desert,
+    Biome::Mountains,
+    Biome::Tundra,
+    Biome::Ocean,
+  ];
+
+  pub fn params(&self) -> BiomeParams {
+    match self {
+      Biome::Plains => BiomeParams {
+        climate: (0.2, 0.2),
+        height_offset: 0.0,
+        amplitude_scale: 0.4,
+        surface: Voxel::new(BlockId::GRASS),
+        subsurface: Voxel::new(BlockId::DIRT),
+      },
+      Biome::Desert => BiomeParams {
+        climate: (0.8, -0.7),
+        height_offset: 5.0,
+        amplitude_scale: 0.3,
+        surface: Voxel::new(BlockId::DESERT_SAND),
+        subsurface: Voxel::new(BlockId::SAND),
+      },
+      Biome::Mountains => BiomeParams {
+        climate: (-0.2, -0.2),
+        height_offset: 40.0,
+        amplitude_scale: 1.6,
+        surface: Voxel::new(BlockId::MOUNTAIN_ROCK),
+        subsurface: Voxel::new(BlockId::GRAVEL),
+      },
+      Biome::Tundra => BiomeParams {
+        climate: (-0.8, 0.3),
+        height_offset: 10.0,
+        amplitude_scale: 0.5,
+        surface: Voxel::new(BlockId::SNOW),
+        subsurface: Voxel::new(BlockId::FROZEN_DIRT),
+      },
+      Biome::Ocean => BiomeParams {
+        climate: (0.3, 0.9),
+        height_offset: -40.0,
+        amplitude_scale: 0.3,
+        surface: Voxel::new(BlockId::SAND),
+        subsurface: Voxel::new(BlockId::SILT),
+      },
+    }
+  }
+}
+
+/// Biome parameters of a single column, blended from all biomes according to
+/// the column's climate.
+pub struct ColumnBiome {
+  /// Biome with the highest weight, decides surface materials
+  pub dominant: Biome,
+  pub height_offset: f32,
+  pub amplitude_scale: f32,
+}
+
+impl ColumnBiome {
+  pub fn from_climate(temperature: f32, humidity: f32) -> Self {
+    let mut total_weight = 0.0;
+    let mut height_offset = 0.0;
+    let mut amplitude_scale = 0.0;
+    let mut dominant = (Biome::Plains, 0.0);
+
+    for biome in Biome::ALL.iter() {
+      let params = biome.params();
+      let dt = temperature - params.climate.0;
+      let dh = humidity - params.climate.1;
+      let weight = (-(dt * dt + dh * dh) * BIOME_BLEND_SHARPNESS).exp();
+
+      total_weight += weight;
+      height_offset += params.height_offset * weight;
+      amplitude_scale += params.amplitude_scale * weight;
+
+      if weight > dominant.1 {
+        dominant = (*biome, weight);
+      }
+    }
+
+    // The weights of all biomes can underflow far away from every biome
+    if total_weight <= f32::EPSILON {
+      let params = dominant.0.params();
+      return Self {
+        dominant: dominant.0,
+        height_offset: params.height_offset,
+        amplitude_scale: params.amplitude_scale,
+      };
+    }
+
+    Self {
+      dominant: dominant.0,
+      height_offset: height_offset / total_weight,
+      amplitude_scale: amplitude_scale / total_weight,
+    }
+  }
+}
+
+fn climate_noise(chunk_pos: IVec2, config: &WorldGenConfig, seed_offset: i32) -> Vec<f32> {
+  let (noise, _, _) = NoiseBuilder::gradient_2d_offset(
+    (chunk_pos.x * CHUNK_SIZE_X) as f32,
+    CHUNK_SIZE_X as usize,
+    (chunk_pos.y * CHUNK_SIZE_Z) as f32,
+    CHUNK_SIZE_Z as usize,
+  )
+  .with_seed(config.seed.wrapping_add(seed_offset))
+  .with_freq(config.biome_frequency)
+  .generate();
+
+  noise
+}
+
+/// Returns blended biome parameters for every column of the chunk, indexed
+/// by `z * CHUNK_SIZE_X + x`.
+pub fn chunk_biomes(chunk_pos: IVec2, config: &WorldGenConfig) -> Vec<ColumnBiome> {
+  let temperature = climate_noise(chunk_pos, config, TEMPERATURE_SEED_OFFSET);
+  let humidity = climate_noise(chunk_pos, config, HUMIDITY_SEED_OFFSET);
+
+  temperature
+    .iter()
+    .zip(humidity.iter())
+    .map(|(t, h)| ColumnBiome::from_climate(t.max(-1.0).min(1.0), h.max(-1.0).min(1.0)))
+    .collect()
+}
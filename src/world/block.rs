@@ -0,0 +1,340 @@
+use bevy::asset::{AssetEvent, AssetLoader, AssetServer, Assets, Handle, LoadContext, LoadedAsset};
+use bevy::ecs::event::EventReader;
+use bevy::ecs::system::{Commands, Res, ResMut};
+use bevy::log::warn;
+use bevy::reflect::TypeUuid;
+use bevy::utils::{BoxedFuture, HashMap};
+use serde::Deserialize;
+use std::sync::Arc;
+
+pub const BLOCK_DEFINITIONS_PATH: &str = "blocks/default.blocks.ron";
+
+/// Index of a block type in the `BlockRegistry`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub u16);
+
+impl BlockId {
+  pub const AIR: BlockId = BlockId(0);
+  pub const GRASS: BlockId = BlockId(1);
+  pub const DIRT: BlockId = BlockId(2);
+  pub const SAND: BlockId = BlockId(3);
+  pub const DESERT_SAND: BlockId = BlockId(4);
+  pub const BEACH_SAND: BlockId = BlockId(5);
+  pub const SILT: BlockId = BlockId(6);
+  pub const SNOW: BlockId = BlockId(7);
+  pub const FROZEN_DIRT: BlockId = BlockId(8);
+  pub const MOUNTAIN_ROCK: BlockId = BlockId(9);
+  pub const GRAVEL: BlockId = BlockId(10);
+  pub const STONE: BlockId = BlockId(11);
+  pub const SLATE: BlockId = BlockId(12);
+  pub const BASALT: BlockId = BlockId(13);
+  pub const BEDROCK: BlockId = BlockId(14);
+  pub const WATER: BlockId = BlockId(15);
+  pub const CHECKER_LIGHT: BlockId = BlockId(16);
+  pub const CHECKER_DARK: BlockId = BlockId(17);
+}
+
+/// Names of the blocks behind the `BlockId` constants, which the world
+/// generator and the mesher refer to directly
+const BUILT_IN_BLOCKS: [(BlockId, &str); 18] = [
+  (BlockId::AIR, "air"),
+  (BlockId::GRASS, "grass"),
+  (BlockId::DIRT, "dirt"),
+  (BlockId::SAND, "sand"),
+  (BlockId::DESERT_SAND, "desert_sand"),
+  (BlockId::BEACH_SAND, "beach_sand"),
+  (BlockId::SILT, "silt"),
+  (BlockId::SNOW, "snow"),
+  (BlockId::FROZEN_DIRT, "frozen_dirt"),
+  (BlockId::MOUNTAIN_ROCK, "mountain_rock"),
+  (BlockId::GRAVEL, "gravel"),
+  (BlockId::STONE, "stone"),
+  (BlockId::SLATE, "slate"),
+  (BlockId::BASALT, "basalt"),
+  (BlockId::BEDROCK, "bedrock"),
+  (BlockId::WATER, "water"),
+  (BlockId::CHECKER_LIGHT, "checker_light"),
+  (BlockId::CHECKER_DARK, "checker_dark"),
+];
+
+#[derive(Debug, Clone)]
+pub struct Block {
+  pub name: String,
+  /// Vertex colour of the block faces
+  pub color: [u8; 4],
+  /// Opaque blocks hide faces of their neighbours
+  pub opaque: bool,
+  /// Transparent blocks are rendered with alpha blending
+  pub transparent: bool,
+  /// Solid blocks can be collided with
+  pub solid: bool,
+  /// Time (in seconds) it takes to break the block, negative for unbreakable
+  pub hardness: f32,
+  /// Asset path of the block texture
+  pub texture: Option<String>,
+}
+
+impl Block {
+  fn new(name: &str, color: [u8; 3], hardness: f32) -> Self {
+    Self {
+      name: name.to_string(),
+      color: [color[0], color[1], color[2], u8::MAX],
+      opaque: true,
+      transparent: false,
+      solid: true,
+      hardness,
+      texture: None,
+    }
+  }
+
+  /// Air-like blocks produce no geometry
+  pub fn is_empty(&self) -> bool {
+    !self.solid && !self.opaque && self.color[3] == 0
+  }
+}
+
+/// Block types of the world, indexed by `BlockId`.
+///
+/// Starts with the built-in blocks and is replaced whenever the block
+/// definitions asset is loaded or modified. Cloning is cheap, so the registry
+/// can be handed over to background tasks.
+#[derive(Clone)]
+pub struct BlockRegistry {
+  blocks: Arc<Vec<Block>>,
+  ids: Arc<HashMap<String, BlockId>>,
+}
+
+impl Default for BlockRegistry {
+  fn default() -> Self {
+    let air = Block {
+      name: "air".to_string(),
+      color: [0; 4],
+      opaque: false,
+      transparent: true,
+      solid: false,
+      hardness: 0.0,
+      texture: None,
+    };
+    let water = Block {
+      name: "water".to_string(),
+      color: [64, 120, 200, 160],
+      opaque: false,
+      transparent: true,
+      solid: false,
+      hardness: -1.0,
+      texture: None,
+    };
+
+    // Order has to match the `BlockId` constants
+    Self::from_blocks(vec![
+      air,
+      Block::new("grass", [99, 146, 103], 0.6),
+      Block::new("dirt", [121, 85, 58], 0.5),
+      Block::new("sand", [194, 178, 128], 0.5),
+      Block::new("desert_sand", [222, 196, 132], 0.5),
+      Block::new("beach_sand", [218, 200, 150], 0.5),
+      Block::new("silt", [160, 150, 110], 0.5),
+      Block::new("snow", [235, 240, 245], 0.2),
+      Block::new("frozen_dirt", [110, 90, 70], 0.8),
+      Block::new("mountain_rock", [120, 120, 120], 1.5),
+      Block::new("gravel", [100, 100, 100], 0.6),
+      Block::new("stone", [128, 128, 128], 1.5),
+      Block::new("slate", [85, 90, 100], 2.0),
+      Block::new("basalt", [55, 55, 60], 2.5),
+      Block::new("bedrock", [25, 25, 25], -1.0),
+      water,
+      Block::new("checker_light", [240, 240, 240], 0.5),
+      Block::new("checker_dark", [30, 30, 30], 0.5),
+    ])
+  }
+}
+
+impl BlockRegistry {
+  pub fn from_blocks(blocks: Vec<Block>) -> Self {
+    let ids = blocks
+      .iter()
+      .enumerate()
+      .map(|(i, block)| (block.name.clone(), BlockId(i as u16)))
+      .collect();
+
+    Self {
+      blocks: Arc::new(blocks),
+      ids: Arc::new(ids),
+    }
+  }
+
+  /// Unknown ids resolve to air
+  #[inline]
+  pub fn get(&self, id: BlockId) -> &Block {
+    self
+      .blocks
+      .get(id.0 as usize)
+      .unwrap_or(&self.blocks[BlockId::AIR.0 as usize])
+  }
+
+  pub fn id(&self, name: &str) -> Option<BlockId> {
+    self.ids.get(name).copied()
+  }
+
+  /// Builds the registry from asset definitions. Ids have to be unique and
+  /// form a continuous range starting at zero, and the built-in blocks have
+  /// to keep the ids of their `BlockId` constants, with air staying empty.
+  pub fn from_definitions(definitions: &BlockDefinitions) -> Result<Self, String> {
+    let mut blocks: Vec<Option<Block>> = vec![None; definitions.blocks.len()];
+
+    for definition in definitions.blocks.iter() {
+      let slot = blocks
+        .get_mut(definition.id as usize)
+        .ok_or_else(|| format!("block {:?} has id out of range", definition.name))?;
+      if slot.is_some() {
+        return Err(format!("duplicate block id {}", definition.id));
+      }
+
+      *slot = Some(Block {
+        name: definition.name.clone(),
+        color: definition.color,
+        opaque: definition.opaque,
+        transparent: definition.transparent,
+        solid: definition.solid,
+        hardness: definition.hardness,
+        texture: definition.texture.clone(),
+      });
+    }
+
+    // All slots are filled, as there is one definition per slot and no
+    // duplicates
+    let registry = Self::from_blocks(blocks.into_iter().flatten().collect());
+
+    for (id, name) in BUILT_IN_BLOCKS.iter() {
+      if registry.id(name) != Some(*id) {
+        return Err(format!("block {:?} has to have id {}", name, id.0));
+      }
+    }
+    if !registry.get(BlockId::AIR).is_empty() {
+      return Err("air has to be an empty block".to_string());
+    }
+
+    Ok(registry)
+  }
+}
+
+fn default_true() -> bool {
+  true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockDefinition {
+  pub id: u16,
+  pub name: String,
+  pub color: [u8; 4],
+  #[serde(default = "default_true")]
+  pub opaque: bool,
+  #[serde(default)]
+  pub transparent: bool,
+  #[serde(default = "default_true")]
+  pub solid: bool,
+  #[serde(default)]
+  pub hardness: f32,
+  #[serde(default)]
+  pub texture: Option<String>,
+}
+
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "7d1f1a3e-4c4b-4f39-9a43-5e3f0c8b2a61"]
+pub struct BlockDefinitions {
+  pub blocks: Vec<BlockDefinition>,
+}
+
+#[derive(Default)]
+pub struct BlockDefinitionsLoader;
+
+impl AssetLoader for BlockDefinitionsLoader {
+  fn load<'a>(
+    &'a self,
+    bytes: &'a [u8],
+    load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+    Box::pin(async move {
+      let definitions: BlockDefinitions = ron::de::from_bytes(bytes)?;
+      load_context.set_default_asset(LoadedAsset::new(definitions));
+      Ok(())
+    })
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["blocks.ron"]
+  }
+}
+
+/// Keeps the block definitions asset loaded (and watched for changes)
+pub struct BlockDefinitionsHandle(pub Handle<BlockDefinitions>);
+
+pub(crate) fn load_block_definitions(mut commands: Commands, asset_server: Res<AssetServer>) {
+  if let Err(err) = asset_server.watch_for_changes() {
+    warn!("Block definitions won't be hot-reloaded: {:?}", err);
+  }
+
+  commands.insert_resource(BlockDefinitionsHandle(
+    asset_server.load(BLOCK_DEFINITIONS_PATH),
+  ));
+}
+
+/// Replaces the `BlockRegistry` whenever the definitions are (re)loaded.
+/// Invalid definitions are reported and the current registry is kept.
+pub(crate) fn update_block_registry(
+  mut events: EventReader<AssetEvent<BlockDefinitions>>,
+  definitions: Res<Assets<BlockDefinitions>>,
+  mut registry: ResMut<BlockRegistry>,
+) {
+  for event in events.iter() {
+    match event {
+      AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+        if let Some(definitions) = definitions.get(handle) {
+          match BlockRegistry::from_definitions(definitions) {
+            Ok(new_registry) => *registry = new_registry,
+            Err(err) => warn!("Invalid block definitions: {}", err),
+          }
+        }
+      }
+      AssetEvent::Removed { .. } => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn default_definitions() -> BlockDefinitions {
+    ron::de::from_str(include_str!("../../assets/blocks/default.blocks.ron")).unwrap()
+  }
+
+  #[test]
+  fn default_definitions_are_valid() {
+    let registry = BlockRegistry::from_definitions(&default_definitions()).unwrap();
+    let built_in = BlockRegistry::default();
+    for (id, name) in BUILT_IN_BLOCKS.iter() {
+      assert_eq!(registry.id(name), Some(*id));
+      assert_eq!(built_in.id(name), Some(*id));
+    }
+  }
+
+  #[test]
+  fn swapped_built_in_ids_are_rejected() {
+    let mut definitions = default_definitions();
+    let water = BlockId::WATER.0 as usize;
+    let bedrock = BlockId::BEDROCK.0 as usize;
+    definitions.blocks[water].id = BlockId::BEDROCK.0;
+    definitions.blocks[bedrock].id = BlockId::WATER.0;
+
+    assert!(BlockRegistry::from_definitions(&definitions).is_err());
+  }
+
+  #[test]
+  fn solid_air_is_rejected() {
+    let mut definitions = default_definitions();
+    definitions.blocks[BlockId::AIR.0 as usize].solid = true;
+
+    assert!(BlockRegistry::from_definitions(&definitions).is_err());
+  }
+}
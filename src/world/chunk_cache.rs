@@ -0,0 +1,51 @@
+use crate::world::ChunkVoxels;
+use bevy::math::IVec2;
+use bevy::utils::HashMap;
+
+/// Voxels of recently unloaded chunks, so chunks coming back into range
+/// don't have to be loaded or generated again. When full, the chunk that
+/// was unloaded the longest time ago is evicted.
+pub struct ChunkCache {
+  capacity: usize,
+  entries: HashMap<IVec2, (ChunkVoxels, u64)>,
+  clock: u64,
+}
+
+impl ChunkCache {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: HashMap::default(),
+      clock: 0,
+    }
+  }
+
+  pub fn insert(&mut self, pos: IVec2, voxels: ChunkVoxels) {
+    if self.capacity == 0 {
+      return;
+    }
+
+    self.clock += 1;
+    self.entries.insert(pos, (voxels, self.clock));
+
+    while self.entries.len() > self.capacity {
+      let oldest = self
+        .entries
+        .iter()
+        .min_by_key(|(_, (_, used))| *used)
+        .map(|(pos, _)| *pos)
+        .unwrap();
+      self.entries.remove(&oldest);
+    }
+  }
+
+  pub fn contains(&self, pos: IVec2) -> bool {
+    self.entries.contains_key(&pos)
+  }
+
+  /// Removes the chunk from the cache, as it is owned by the loaded chunk
+  /// again
+  pub fn take(&mut self, pos: IVec2) -> Option<ChunkVoxels> {
+    self.entries.remove(&pos).map(|(voxels, _)| voxels)
+  }
+}
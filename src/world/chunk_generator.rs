@@ -1,51 +1,263 @@
-use crate::world::{Chunk, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z, WORLD_RESOLUTION};
-use bevy::prelude::Mut;
+use crate::config::{TerrainKind, WorldGenConfig};
+use crate::world::biome::{chunk_biomes, ColumnBiome};
+use crate::world::density_generator::DensityGenerator;
+use crate::world::palette::ChunkVoxels;
+use crate::world::strata::apply_strata;
+use crate::world::water::fill_sea;
+use crate::world::{BlockId, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
 use building_blocks::core::{ExtentN, PointN};
-use building_blocks::prelude::FillExtent;
 use simdnoise::NoiseBuilder;
+use std::sync::Arc;
 
-const LEVEL_SEED: i32 = 0;
-const GROUND_LEVEL: u32 = 100;
-const NOISE_GROUND_MAX_OFFSET: u32 = 50;
+const SAND_VOXEL: Voxel = Voxel::new(BlockId::SAND);
+const GRASS_VOXEL: Voxel = Voxel::new(BlockId::GRASS);
 
-const NOISE_MIN: f32 = (GROUND_LEVEL - NOISE_GROUND_MAX_OFFSET) as f32;
-const NOISE_MAX: f32 = (GROUND_LEVEL + NOISE_GROUND_MAX_OFFSET) as f32;
+/// Produces the voxel data of a single chunk column.
+///
+/// `voxels` covers the padded chunk extent and is expected to be filled with
+/// empty voxels when passed in.
+pub trait ChunkGenerator: Send + Sync {
+  fn generate(&self, chunk_pos: IVec2, voxels: &mut ChunkVoxels);
+}
+
+/// Generator used by `generate_chunks`. Insert it before adding
+/// `VoxelWorldPlugin` to replace the generator selected by `WorldGenConfig`;
+/// chunks of such a world are only saved when a `RegionStorage` is inserted
+/// as well.
+#[derive(Clone)]
+pub struct WorldGenerator(pub Arc<dyn ChunkGenerator>);
+
+impl WorldGenerator {
+  pub fn new<G: ChunkGenerator + 'static>(generator: G) -> Self {
+    Self(Arc::new(generator))
+  }
+
+  /// Builds the generator selected by `config.terrain`
+  pub fn from_config(config: &WorldGenConfig) -> Self {
+    match config.terrain {
+      TerrainKind::Heightmap => Self::new(HeightmapGenerator::new(config.clone())),
+      TerrainKind::Density => Self::new(DensityGenerator::new(config.clone())),
+      TerrainKind::Flat => Self::new(FlatWorldGenerator {
+        height: config.base_height.round() as i32,
+        ..Default::default()
+      }),
+      TerrainKind::Checkerboard => Self::new(CheckerboardGenerator {
+        height: config.base_height.round() as i32,
+        ..Default::default()
+      }),
+    }
+  }
+}
+
+fn fill_column(voxels: &mut ChunkVoxels, x: i32, z: i32, min_y: i32, max_y: i32, voxel: Voxel) {
+  voxels.fill_extent(
+    &ExtentN::from_min_and_max(PointN([x, min_y, z]), PointN([x, max_y, z])),
+    voxel,
+  );
+}
+
+pub(crate) fn fill_ground_layer(voxels: &mut ChunkVoxels) {
+  voxels.fill_extent(
+    &ExtentN::from_min_and_max(PointN([0; 3]), PointN([CHUNK_SIZE_X, 0, CHUNK_SIZE_Z])),
+    SAND_VOXEL,
+  );
+}
+
+/// 2D fbm noise heightmap driven by `WorldGenConfig`, with height profile and
+/// surface materials of each column decided by its biome
+pub struct HeightmapGenerator {
+  config: WorldGenConfig,
+}
+
+impl HeightmapGenerator {
+  pub fn new(config: WorldGenConfig) -> Self {
+    Self { config }
+  }
+}
 
-pub(crate) fn generate_chunk(mut chunk: Mut<Chunk>) {
+/// Terrain surface height of every column of the chunk together with the
+/// column's biome, indexed by `z * CHUNK_SIZE_X + x`.
+pub(crate) fn surface_heights(
+  chunk_pos: IVec2,
+  config: &WorldGenConfig,
+) -> (Vec<f32>, Vec<ColumnBiome>) {
   let (noise, _, _) = NoiseBuilder::fbm_2d_offset(
-    (chunk.pos.x * CHUNK_SIZE_X) as f32,
+    (chunk_pos.x * CHUNK_SIZE_X) as f32,
     CHUNK_SIZE_X as usize,
-    (chunk.pos.y * CHUNK_SIZE_Z) as f32,
+    (chunk_pos.y * CHUNK_SIZE_Z) as f32,
     CHUNK_SIZE_Z as usize,
   )
-  .with_seed(LEVEL_SEED)
-  .with_octaves(5)
-  .with_freq(0.02 / (WORLD_RESOLUTION as f32))
+  .with_seed(config.seed)
+  .with_octaves(config.octaves)
+  .with_freq(config.frequency)
+  .with_lacunarity(config.lacunarity)
+  .with_gain(config.gain)
   .generate();
 
-  for z in 0..CHUNK_SIZE_Z {
-    for x in 0..CHUNK_SIZE_X {
-      let height = (noise.get((z * CHUNK_SIZE_X + x) as usize).unwrap() + 1.0)
-        * (NOISE_MAX - NOISE_MIN)
-        + NOISE_MIN;
-
-      let block_height = (height.round() as i32).max(0).min(CHUNK_SIZE_Y - 1);
-      // println!("pos: ({}, {}) -> {}", x, z, block_height);
-
-      // Put zeroth level
-      chunk.block_data.fill_extent(
-        &ExtentN::from_min_and_max(PointN([0; 3]), PointN([CHUNK_SIZE_X, 0, CHUNK_SIZE_Z])),
-        Voxel {
-          attributes: [194, 178, 128, 255],
-        },
-      );
-
-      chunk.block_data.fill_extent(
-        &ExtentN::from_min_and_max(PointN([x, 1, z]), PointN([x, block_height, z])),
-        Voxel {
-          attributes: [99, 146, 103, 255],
-        },
-      )
+  let biomes = chunk_biomes(chunk_pos, config);
+
+  let heights = noise
+    .iter()
+    .zip(biomes.iter())
+    .map(|(n, biome)| {
+      let amplitude = config.amplitude * biome.amplitude_scale;
+      let noise_min = config.base_height + biome.height_offset - amplitude;
+      let noise_max = config.base_height + biome.height_offset + amplitude;
+
+      (n + 1.0) * (noise_max - noise_min) + noise_min
+    })
+    .collect();
+
+  (heights, biomes)
+}
+
+impl ChunkGenerator for HeightmapGenerator {
+  fn generate(&self, chunk_pos: IVec2, voxels: &mut ChunkVoxels) {
+    let (heights, biomes) = surface_heights(chunk_pos, &self.config);
+
+    // Put zeroth level
+    fill_ground_layer(voxels);
+
+    for z in 0..CHUNK_SIZE_Z {
+      for x in 0..CHUNK_SIZE_X {
+        let idx = (z * CHUNK_SIZE_X + x) as usize;
+        let block_height = (heights[idx].round() as i32).max(1).min(CHUNK_SIZE_Y - 1);
+        // println!("pos: ({}, {}) -> {}", x, z, block_height);
+
+        let surface = biomes[idx].dominant.params().surface;
+        fill_column(voxels, x, z, 1, block_height, surface);
+      }
+    }
+
+    apply_strata(chunk_pos, &self.config, &biomes, voxels);
+    fill_sea(voxels, self.config.sea_level);
+  }
+}
+
+/// Fills every column up to the same height with a single voxel type
+pub struct FlatWorldGenerator {
+  pub height: i32,
+  pub voxel: Voxel,
+}
+
+impl Default for FlatWorldGenerator {
+  fn default() -> Self {
+    Self {
+      height: 100,
+      voxel: GRASS_VOXEL,
+    }
+  }
+}
+
+impl ChunkGenerator for FlatWorldGenerator {
+  fn generate(&self, _chunk_pos: IVec2, voxels: &mut ChunkVoxels) {
+    fill_ground_layer(voxels);
+    voxels.fill_extent(
+      &ExtentN::from_min_and_max(
+        PointN([0, 1, 0]),
+        PointN([
+          CHUNK_SIZE_X - 1,
+          self.height.max(1).min(CHUNK_SIZE_Y - 1),
+          CHUNK_SIZE_Z - 1,
+        ]),
+      ),
+      self.voxel,
+    );
+  }
+}
+
+/// Flat world with alternating voxel colours, useful for checking chunk
+/// placement and meshing. Colours are swapped between neighbouring chunks so
+/// chunk borders are visible.
+pub struct CheckerboardGenerator {
+  pub height: i32,
+  pub colors: [Voxel; 2],
+}
+
+impl Default for CheckerboardGenerator {
+  fn default() -> Self {
+    Self {
+      height: 100,
+      colors: [
+        Voxel::new(BlockId::CHECKER_LIGHT),
+        Voxel::new(BlockId::CHECKER_DARK),
+      ],
+    }
+  }
+}
+
+impl ChunkGenerator for CheckerboardGenerator {
+  fn generate(&self, chunk_pos: IVec2, voxels: &mut ChunkVoxels) {
+    let height = self.height.max(0).min(CHUNK_SIZE_Y - 1);
+    let chunk_parity = (chunk_pos.x + chunk_pos.y).rem_euclid(2);
+
+    for z in 0..CHUNK_SIZE_Z {
+      for x in 0..CHUNK_SIZE_X {
+        let parity = ((x + z).rem_euclid(2) + chunk_parity) % 2;
+        fill_column(voxels, x, z, 0, height, self.colors[parity as usize]);
+      }
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn generate(generator: &dyn ChunkGenerator, chunk_pos: IVec2) -> ChunkVoxels {
+    let mut voxels = ChunkVoxels::new(Voxel::default());
+    generator.generate(chunk_pos, &mut voxels);
+    voxels
+  }
+
+  #[test]
+  fn flat_world_fills_up_to_height() {
+    let generator = FlatWorldGenerator {
+      height: 10,
+      ..Default::default()
+    };
+    let voxels = generate(&generator, IVec2::new(-3, 5));
+
+    assert_eq!(voxels.get(PointN([4, 0, 4])), SAND_VOXEL);
+    assert_eq!(voxels.get(PointN([4, 10, 4])), GRASS_VOXEL);
+    assert_eq!(voxels.get(PointN([4, 11, 4])), Voxel::default());
+  }
+
+  #[test]
+  fn checkerboard_alternates_across_chunks() {
+    let generator = CheckerboardGenerator::default();
+    let [light, dark] = generator.colors;
+
+    let positions = [
+      (IVec2::new(0, 0), light),
+      (IVec2::new(1, 0), dark),
+      (IVec2::new(-1, 0), dark),
+      (IVec2::new(0, -1), dark),
+      (IVec2::new(-1, -1), light),
+      (IVec2::new(-3, 2), dark),
+    ];
+    for (chunk_pos, expected) in positions.iter() {
+      let voxels = generate(&generator, *chunk_pos);
+      assert_eq!(voxels.get(PointN([0, 0, 0])), *expected, "{}", chunk_pos);
+      assert_ne!(voxels.get(PointN([1, 0, 0])), *expected, "{}", chunk_pos);
+      assert_eq!(voxels.get(PointN([1, 0, 1])), *expected, "{}", chunk_pos);
+    }
+  }
+
+  #[test]
+  fn terrain_kind_selects_generator() {
+    let config = WorldGenConfig {
+      terrain: TerrainKind::Checkerboard,
+      base_height: 20.0,
+      ..Default::default()
+    };
+    let voxels = generate(&*WorldGenerator::from_config(&config).0, IVec2::ZERO);
+
+    assert_eq!(
+      voxels.get(PointN([0, 20, 0])),
+      Voxel::new(BlockId::CHECKER_LIGHT)
+    );
+    assert_eq!(voxels.get(PointN([0, 21, 0])), Voxel::default());
+  }
+}
@@ -0,0 +1,89 @@
+use bevy::ecs::entity::Entity;
+use bevy::ecs::event::EventWriter;
+use bevy::log::warn;
+use bevy::math::IVec2;
+use bevy::prelude::Component;
+
+/// Lifecycle of a chunk:
+///
+/// `Requested` → `Loading` → `Generated` → `Meshing` ⇄ `Ready`
+///
+/// Any state but `Unloading` itself can move to `Unloading`, which cancels
+/// the work in flight. Every transition is reported as a
+/// `ChunkStateChanged` event.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkState {
+  /// Spawned and queued for loading
+  Requested,
+  /// Voxels are being loaded from disk or generated
+  Loading,
+  /// Voxels are available, meshing hasn't started yet
+  Generated,
+  /// Some sections of the chunk are queued for meshing or being meshed
+  Meshing,
+  /// Voxels and meshes are up to date
+  Ready,
+  /// Marked for despawning
+  Unloading,
+}
+
+impl ChunkState {
+  pub fn can_transition_to(self, next: ChunkState) -> bool {
+    use ChunkState::*;
+
+    match (self, next) {
+      (Requested, Loading)
+      | (Loading, Generated)
+      | (Generated, Meshing)
+      | (Meshing, Ready)
+      | (Ready, Meshing) => true,
+      (_, Unloading) => self != Unloading,
+      _ => false,
+    }
+  }
+
+  /// Voxels of the chunk can be read
+  #[inline]
+  pub fn has_voxels(self) -> bool {
+    matches!(
+      self,
+      ChunkState::Generated | ChunkState::Meshing | ChunkState::Ready
+    )
+  }
+
+  /// Moves the chunk to `next` and reports the transition. Invalid
+  /// transitions are logged and leave the state unchanged.
+  pub fn transition(
+    &mut self,
+    next: ChunkState,
+    entity: Entity,
+    pos: IVec2,
+    events: &mut EventWriter<ChunkStateChanged>,
+  ) -> bool {
+    if !self.can_transition_to(next) {
+      warn!(
+        "Invalid state transition of chunk {:?}: {:?} -> {:?}",
+        pos, self, next
+      );
+      return false;
+    }
+
+    events.send(ChunkStateChanged {
+      entity,
+      pos,
+      from: *self,
+      to: next,
+    });
+    *self = next;
+    true
+  }
+}
+
+/// Sent on every transition of a `ChunkState`
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkStateChanged {
+  pub entity: Entity,
+  pub pos: IVec2,
+  pub from: ChunkState,
+  pub to: ChunkState,
+}
@@ -0,0 +1,166 @@
+//! Conversions between world space, chunk positions and voxel coordinates
+//! within a chunk.
+//!
+//! World space is measured in coordinate units, one unit being the edge of a
+//! voxel. A meter spans `WORLD_RESOLUTION` units. Conversions round towards
+//! negative infinity, so e.g. `x = -0.5` belongs to voxel `-1` and chunk `-1`.
+
+use crate::world::{CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::{IVec2, IVec3, Vec3};
+use building_blocks::core::{Point3i, PointN};
+
+/// Position in world space
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WorldPos(pub Vec3);
+
+impl WorldPos {
+  /// World coordinate of the voxel containing the position
+  #[inline]
+  pub fn voxel(self) -> IVec3 {
+    IVec3::new(
+      self.0.x.floor() as i32,
+      self.0.y.floor() as i32,
+      self.0.z.floor() as i32,
+    )
+  }
+
+  /// Chunk column containing the position
+  #[inline]
+  pub fn chunk(self) -> ChunkPos {
+    ChunkPos::from_voxel(self.voxel())
+  }
+}
+
+/// Position of a chunk column, in chunks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkPos(pub IVec2);
+
+impl ChunkPos {
+  /// Chunk column containing a world voxel coordinate
+  #[inline]
+  pub fn from_voxel(voxel: IVec3) -> Self {
+    Self(IVec2::new(
+      voxel.x.div_euclid(CHUNK_SIZE_X),
+      voxel.z.div_euclid(CHUNK_SIZE_Z),
+    ))
+  }
+
+  /// World coordinate of the chunk voxel at local `[0, 0, 0]`
+  #[inline]
+  pub fn min_voxel(self) -> IVec3 {
+    IVec3::new(self.0.x * CHUNK_SIZE_X, 0, self.0.y * CHUNK_SIZE_Z)
+  }
+
+  /// Position of the chunk origin in world space
+  #[inline]
+  pub fn world_origin(self) -> WorldPos {
+    let min = self.min_voxel();
+    WorldPos(Vec3::new(min.x as f32, min.y as f32, min.z as f32))
+  }
+
+  /// World coordinate of a voxel within the chunk
+  #[inline]
+  pub fn voxel(self, local: LocalVoxelPos) -> IVec3 {
+    self.min_voxel() + local.0
+  }
+}
+
+/// Voxel coordinate within a chunk column, within `chunk_extent()`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocalVoxelPos(pub IVec3);
+
+impl LocalVoxelPos {
+  /// Splits a world voxel coordinate into its chunk and the coordinate
+  /// within the chunk. Returns `None` above or below the world.
+  #[inline]
+  pub fn split(voxel: IVec3) -> Option<(ChunkPos, LocalVoxelPos)> {
+    if voxel.y < 0 || voxel.y >= CHUNK_SIZE_Y {
+      return None;
+    }
+
+    let local = IVec3::new(
+      voxel.x.rem_euclid(CHUNK_SIZE_X),
+      voxel.y,
+      voxel.z.rem_euclid(CHUNK_SIZE_Z),
+    );
+    Some((ChunkPos::from_voxel(voxel), LocalVoxelPos(local)))
+  }
+
+  /// Point for indexing `ChunkVoxels`
+  #[inline]
+  pub fn point(self) -> Point3i {
+    PointN(self.0.into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Voxels around the origin, covering several chunks in every direction
+  fn voxels() -> impl Iterator<Item = IVec3> {
+    let range = -3 * CHUNK_SIZE_X - 5..3 * CHUNK_SIZE_X + 5;
+    range.clone().flat_map(move |x| {
+      range.clone().flat_map(move |z| {
+        IntoIterator::into_iter([0, 1, CHUNK_SIZE_Y - 1]).map(move |y| IVec3::new(x, y, z))
+      })
+    })
+  }
+
+  #[test]
+  fn split_round_trips() {
+    for voxel in voxels() {
+      let (chunk, local) = LocalVoxelPos::split(voxel).unwrap();
+      assert_eq!(chunk.voxel(local), voxel, "{:?}", voxel);
+      assert_eq!(ChunkPos::from_voxel(voxel), chunk);
+    }
+  }
+
+  #[test]
+  fn local_coordinates_stay_in_chunk() {
+    for voxel in voxels() {
+      let (chunk, LocalVoxelPos(local)) = LocalVoxelPos::split(voxel).unwrap();
+      assert!((0..CHUNK_SIZE_X).contains(&local.x), "{:?}", voxel);
+      assert!((0..CHUNK_SIZE_Z).contains(&local.z), "{:?}", voxel);
+      assert_eq!(local.y, voxel.y);
+
+      let min = chunk.min_voxel();
+      assert!(min.x <= voxel.x && voxel.x < min.x + CHUNK_SIZE_X);
+      assert!(min.z <= voxel.z && voxel.z < min.z + CHUNK_SIZE_Z);
+    }
+  }
+
+  #[test]
+  fn split_rejects_voxels_outside_of_world_height() {
+    assert!(LocalVoxelPos::split(IVec3::new(0, -1, 0)).is_none());
+    assert!(LocalVoxelPos::split(IVec3::new(-5, CHUNK_SIZE_Y, 7)).is_none());
+  }
+
+  #[test]
+  fn world_positions_round_towards_negative_infinity() {
+    let pos = WorldPos(Vec3::new(-0.5, 10.0, -0.5));
+    assert_eq!(pos.voxel(), IVec3::new(-1, 10, -1));
+    assert_eq!(pos.chunk(), ChunkPos(IVec2::new(-1, -1)));
+
+    let pos = WorldPos(Vec3::new(0.5, 10.0, CHUNK_SIZE_Z as f32 - 0.5));
+    assert_eq!(pos.chunk(), ChunkPos(IVec2::new(0, 0)));
+
+    let pos = WorldPos(Vec3::new(
+      -(CHUNK_SIZE_X as f32),
+      0.0,
+      -(CHUNK_SIZE_Z as f32) - 0.25,
+    ));
+    assert_eq!(pos.chunk(), ChunkPos(IVec2::new(-1, -2)));
+  }
+
+  #[test]
+  fn chunk_origin_is_min_voxel() {
+    for x in -4..4 {
+      for z in -4..4 {
+        let chunk = ChunkPos(IVec2::new(x, z));
+        assert_eq!(chunk.world_origin().chunk(), chunk);
+        assert_eq!(chunk.world_origin().voxel(), chunk.min_voxel());
+      }
+    }
+  }
+}
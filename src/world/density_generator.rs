@@ -0,0 +1,107 @@
+use crate::config::WorldGenConfig;
+use crate::world::chunk_generator::{fill_ground_layer, surface_heights, ChunkGenerator};
+use crate::world::palette::ChunkVoxels;
+use crate::world::strata::apply_strata;
+use crate::world::water::fill_sea;
+use crate::world::{Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
+use building_blocks::core::PointN;
+use simdnoise::NoiseBuilder;
+
+const DENSITY_OCTAVES: u8 = 3;
+
+const DENSITY_SEED_OFFSET: i32 = 3;
+const CAVE_SEED_OFFSETS: [i32; 2] = [4, 5];
+
+/// Caves are not carved into the lowest layers so the world keeps a floor
+const CAVE_MIN_Y: i32 = 3;
+
+/// 3D terrain where a voxel is solid when its density is positive.
+///
+/// The density is a gradient falling off above the biome surface height,
+/// perturbed by 3D fbm noise to create overhangs and arches. Caves are carved
+/// where two independent noise fields are both close to zero, which produces
+/// long tunnels instead of flat sheets.
+pub struct DensityGenerator {
+  config: WorldGenConfig,
+}
+
+impl DensityGenerator {
+  pub fn new(config: WorldGenConfig) -> Self {
+    Self { config }
+  }
+
+  fn noise_3d(&self, chunk_pos: IVec2, seed_offset: i32, freq: f32, octaves: u8) -> Vec<f32> {
+    let (noise, _, _) = NoiseBuilder::fbm_3d_offset(
+      (chunk_pos.x * CHUNK_SIZE_X) as f32,
+      CHUNK_SIZE_X as usize,
+      0.0,
+      CHUNK_SIZE_Y as usize,
+      (chunk_pos.y * CHUNK_SIZE_Z) as f32,
+      CHUNK_SIZE_Z as usize,
+    )
+    .with_seed(self.config.seed.wrapping_add(seed_offset))
+    .with_freq(freq)
+    .with_octaves(octaves)
+    .with_lacunarity(2.0)
+    .with_gain(0.5)
+    .generate();
+
+    noise
+  }
+}
+
+#[inline]
+fn noise_index(x: i32, y: i32, z: i32) -> usize {
+  ((z * CHUNK_SIZE_Y + y) * CHUNK_SIZE_X + x) as usize
+}
+
+impl ChunkGenerator for DensityGenerator {
+  fn generate(&self, chunk_pos: IVec2, voxels: &mut ChunkVoxels) {
+    let config = &self.config;
+    let (heights, biomes) = surface_heights(chunk_pos, config);
+    let density_noise = self.noise_3d(
+      chunk_pos,
+      DENSITY_SEED_OFFSET,
+      config.density_frequency,
+      DENSITY_OCTAVES,
+    );
+    let cave_noise = [
+      self.noise_3d(chunk_pos, CAVE_SEED_OFFSETS[0], config.cave_frequency, 1),
+      self.noise_3d(chunk_pos, CAVE_SEED_OFFSETS[1], config.cave_frequency, 1),
+    ];
+
+    fill_ground_layer(voxels);
+
+    for z in 0..CHUNK_SIZE_Z {
+      for x in 0..CHUNK_SIZE_X {
+        let column = (z * CHUNK_SIZE_X + x) as usize;
+        let params = biomes[column].dominant.params();
+
+        // Noise can only push the surface `density_strength` voxels up, so
+        // there is nothing to evaluate above that
+        let max_y = ((heights[column] + config.density_strength).ceil() as i32)
+          .max(1)
+          .min(CHUNK_SIZE_Y - 1);
+
+        for y in (1..=max_y).rev() {
+          let idx = noise_index(x, y, z);
+          let density = (heights[column] - y as f32) + density_noise[idx] * config.density_strength;
+
+          let is_cave = y >= CAVE_MIN_Y
+            && cave_noise[0][idx].abs() < config.cave_threshold
+            && cave_noise[1][idx].abs() < config.cave_threshold;
+
+          if density <= 0.0 || is_cave {
+            continue;
+          }
+
+          voxels.set(PointN([x, y, z]), params.surface);
+        }
+      }
+    }
+
+    apply_strata(chunk_pos, config, &biomes, voxels);
+    fill_sea(voxels, config.sea_level);
+  }
+}
@@ -0,0 +1,202 @@
+use crate::player::PlayerCamera;
+use crate::world::{ChunkLoader, ChunkLoaderMotion, ChunkPos, CHUNK_SIZE_X, CHUNK_SIZE_Z};
+use bevy::ecs::entity::Entity;
+use bevy::ecs::system::{Query, ResMut};
+use bevy::math::{IVec2, Vec2, Vec3};
+use bevy::prelude::{GlobalTransform, Parent, With};
+use bevy::render::camera::PerspectiveProjection;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Distance multiplier of chunks outside the camera frustum
+const OUT_OF_VIEW_PENALTY: f32 = 2.0;
+/// Largest distance reduction of chunks straight along the direction of
+/// travel, as a fraction of their distance
+const TRAVEL_BONUS: f32 = 0.5;
+/// Slowest horizontal speed, in world units per second, at which a loader
+/// counts as travelling
+const MIN_TRAVEL_SPEED: f32 = 1.0;
+/// Radius of the circle enclosing a chunk column, in chunks
+const CHUNK_RADIUS: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Center of a chunk column in world space, on the horizontal plane
+#[inline]
+fn chunk_center(pos: IVec2) -> Vec2 {
+  let origin = ChunkPos(pos).world_origin().0;
+  Vec2::new(
+    origin.x + CHUNK_SIZE_X as f32 / 2.0,
+    origin.z + CHUNK_SIZE_Z as f32 / 2.0,
+  )
+}
+
+/// Horizontal part of a world space vector, normalized. `None` if the
+/// vector is (close to) vertical.
+#[inline]
+fn horizontal_direction(v: Vec3) -> Option<Vec2> {
+  let v = Vec2::new(v.x, v.z);
+  if v.length_squared() > 1e-6 {
+    Some(v.normalize())
+  } else {
+    None
+  }
+}
+
+/// Point of view of a `ChunkLoader` used to prioritise chunk loads
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LoadFocus {
+  /// Position of the loader on the horizontal plane, in world space
+  position: Vec2,
+  /// Horizontal view direction and half of the horizontal field of view of
+  /// the player camera, if the loader carries one
+  view: Option<(Vec2, f32)>,
+  /// Horizontal direction the loader is moving in
+  travel: Option<Vec2>,
+}
+
+impl LoadFocus {
+  /// Distance to the chunk in chunks, scaled down along the direction of
+  /// travel and up outside of the view. Lower is loaded earlier.
+  fn priority(&self, pos: IVec2) -> f32 {
+    let offset = (chunk_center(pos) - self.position) / CHUNK_SIZE_X as f32;
+    let distance = offset.length();
+    if distance <= CHUNK_RADIUS {
+      return distance;
+    }
+    let direction = offset / distance;
+
+    let mut priority = distance;
+    if let Some((forward, half_fov)) = self.view {
+      // Angular slack for the chunk extent, so chunks partially in view
+      // count as visible
+      let slack = (CHUNK_RADIUS / distance).asin();
+      if forward.dot(direction).clamp(-1.0, 1.0).acos() > half_fov + slack {
+        priority *= OUT_OF_VIEW_PENALTY;
+      }
+    }
+    if let Some(travel) = self.travel {
+      priority *= 1.0 - TRAVEL_BONUS * travel.dot(direction).max(0.0);
+    }
+    priority
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct QueuedChunk {
+  priority: f32,
+  entity: Entity,
+  pos: IVec2,
+}
+
+impl PartialEq for QueuedChunk {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for QueuedChunk {}
+
+impl PartialOrd for QueuedChunk {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for QueuedChunk {
+  // Reversed, so the max-heap pops the lowest priority value first
+  fn cmp(&self, other: &Self) -> Ordering {
+    other
+      .priority
+      .partial_cmp(&self.priority)
+      .unwrap_or(Ordering::Equal)
+  }
+}
+
+/// Chunks waiting for their voxels, ordered by their distance to the
+/// closest `ChunkLoader`, weighted by the loader's view and direction of
+/// travel. Priorities are recomputed whenever the loaders move or turn.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkLoadQueue {
+  heap: BinaryHeap<QueuedChunk>,
+  foci: Vec<LoadFocus>,
+}
+
+impl ChunkLoadQueue {
+  fn priority(&self, pos: IVec2) -> f32 {
+    self
+      .foci
+      .iter()
+      .map(|focus| focus.priority(pos))
+      .fold(f32::INFINITY, f32::min)
+  }
+
+  pub fn push(&mut self, entity: Entity, pos: IVec2) {
+    let priority = self.priority(pos);
+    self.heap.push(QueuedChunk {
+      priority,
+      entity,
+      pos,
+    });
+  }
+
+  /// Removes the chunk with the highest priority
+  pub fn pop(&mut self) -> Option<Entity> {
+    self.heap.pop().map(|queued| queued.entity)
+  }
+
+  pub fn remove(&mut self, entity: Entity) {
+    let mut queued = std::mem::take(&mut self.heap).into_vec();
+    queued.retain(|queued| queued.entity != entity);
+    self.heap = BinaryHeap::from(queued);
+  }
+
+  pub fn len(&self) -> usize {
+    self.heap.len()
+  }
+
+  /// Replaces the loader points of view and reorders the queued chunks
+  fn set_foci(&mut self, foci: Vec<LoadFocus>) {
+    self.foci = foci;
+
+    let mut queued = std::mem::take(&mut self.heap).into_vec();
+    for chunk in queued.iter_mut() {
+      chunk.priority = self.priority(chunk.pos);
+    }
+    self.heap = BinaryHeap::from(queued);
+  }
+}
+
+/// Updates the points of view of all chunk loaders and reorders the load
+/// queue accordingly. The frustum test only considers the horizontal field
+/// of view, as chunks span the whole world height.
+pub(crate) fn prioritise_chunk_loads(
+  loaders: Query<(Entity, &GlobalTransform, Option<&ChunkLoaderMotion>), With<ChunkLoader>>,
+  cameras: Query<(&Parent, &GlobalTransform, &PerspectiveProjection), With<PlayerCamera>>,
+  mut queue: ResMut<ChunkLoadQueue>,
+) {
+  let mut foci = Vec::new();
+
+  for (entity, transform, motion) in loaders.iter() {
+    let position = transform.translation;
+    let travel = motion
+      .map(|motion| motion.velocity())
+      .filter(|velocity| Vec2::new(velocity.x, velocity.z).length() >= MIN_TRAVEL_SPEED)
+      .and_then(horizontal_direction);
+
+    let view = cameras
+      .iter()
+      .find(|(parent, _, _)| parent.0 == entity)
+      .and_then(|(_, camera, projection)| {
+        let forward = horizontal_direction(camera.rotation.mul_vec3(-Vec3::Z))?;
+        let half_fov = ((projection.fov / 2.0).tan() * projection.aspect_ratio).atan();
+        Some((forward, half_fov))
+      });
+
+    foci.push(LoadFocus {
+      position: Vec2::new(position.x, position.z),
+      view,
+      travel,
+    });
+  }
+
+  queue.set_foci(foci);
+}
@@ -1,6 +1,40 @@
+mod biome;
+mod block;
+mod chunk_cache;
 mod chunk_generator;
+mod chunk_state;
+mod coordinates;
+mod density_generator;
+mod load_queue;
+mod palette;
+mod raycast;
+mod scheduler;
+mod storage;
+mod strata;
+mod water;
 mod world;
 
+pub use biome::Biome;
+pub use block::{
+  Block, BlockDefinition, BlockDefinitions, BlockDefinitionsHandle, BlockId, BlockRegistry,
+};
+pub use chunk_cache::ChunkCache;
+pub use chunk_generator::{
+  CheckerboardGenerator, ChunkGenerator, FlatWorldGenerator, HeightmapGenerator, WorldGenerator,
+};
+pub use chunk_state::{ChunkState, ChunkStateChanged};
+pub use coordinates::{ChunkPos, LocalVoxelPos, WorldPos};
+pub use density_generator::DensityGenerator;
+pub use palette::{ChunkVoxels, SECTIONS_PER_CHUNK, SECTION_HEIGHT};
+pub use raycast::RaycastHit;
+pub use scheduler::{
+  ChunkWork, ChunkWorkScheduler, DIAGNOSTIC_GENERATION_COST, DIAGNOSTIC_GENERATION_QUEUE,
+  DIAGNOSTIC_GENERATION_THROUGHPUT, DIAGNOSTIC_MESHING_COST, DIAGNOSTIC_MESHING_QUEUE,
+  DIAGNOSTIC_MESHING_THROUGHPUT,
+};
+pub use storage::{RegionStorage, REGION_SIZE};
+pub use strata::Stratum;
+pub use water::WATER_VOXEL;
 pub use world::*;
 
 /// WORLD_RESOLUTION defines ratio between coordinates and real in-game size
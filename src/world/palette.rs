@@ -0,0 +1,504 @@
+use crate::world::{chunk_extent, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
+use building_blocks::core::{Extent3i, Point3i, PointN};
+#[cfg(test)]
+use std::mem::size_of;
+
+/// Height of a single chunk section
+pub const SECTION_HEIGHT: i32 = 16;
+/// Number of sections in a chunk column, not counting the padding
+pub const SECTIONS_PER_CHUNK: i32 = CHUNK_SIZE_Y / SECTION_HEIGHT;
+
+// Storage covers the padded chunk extent
+const SIZE_X: i32 = CHUNK_SIZE_X + 2;
+const SIZE_Z: i32 = CHUNK_SIZE_Z + 2;
+
+// Section `i` of the chunk is stored at `i + 1`, the first and last stored
+// sections only hold the bottom and top padding layer
+const SECTION_COUNT: usize = (SECTIONS_PER_CHUNK + 2) as usize;
+const SECTION_VOLUME: usize = (SIZE_X * SECTION_HEIGHT * SIZE_Z) as usize;
+
+/// Stored section and index of a voxel within it. Storage coordinates start
+/// at the padded extent minimum, which is `-1` on every axis.
+#[inline]
+fn locate(p: Point3i) -> (usize, usize) {
+  let [x, y, z] = p.0;
+  let (x, z) = (x + 1, z + 1);
+  debug_assert!(x >= 0 && x < SIZE_X && y >= -1 && y <= CHUNK_SIZE_Y && z >= 0 && z < SIZE_Z);
+
+  let section = (y.div_euclid(SECTION_HEIGHT) + 1) as usize;
+  let index = ((y.rem_euclid(SECTION_HEIGHT) * SIZE_Z + z) * SIZE_X + x) as usize;
+  (section, index)
+}
+
+/// Layer of the chunk at `x` (or `z`) coordinate `coord`, perpendicular to
+/// the horizontal unit offset `side`. Padding is excluded.
+fn side_layer(side: IVec2, coord: i32) -> Extent3i {
+  let (min, shape) = if side.x != 0 {
+    ([coord, 0, 0], [1, CHUNK_SIZE_Y, CHUNK_SIZE_Z])
+  } else {
+    ([0, 0, coord], [CHUNK_SIZE_X, CHUNK_SIZE_Y, 1])
+  };
+  Extent3i::from_min_and_shape(PointN(min), PointN(shape))
+}
+
+/// Padding layer towards the neighbour at the horizontal unit offset `side`
+fn padding_layer(side: IVec2) -> Extent3i {
+  let coord = if side.x + side.y > 0 {
+    if side.x != 0 {
+      CHUNK_SIZE_X
+    } else {
+      CHUNK_SIZE_Z
+    }
+  } else {
+    -1
+  };
+  side_layer(side, coord)
+}
+
+/// Lowest y coordinate of a stored section
+#[inline]
+fn stored_section_min_y(section: usize) -> i32 {
+  (section as i32 - 1) * SECTION_HEIGHT
+}
+
+#[derive(Clone, Debug)]
+enum Section {
+  /// Every voxel of the section has the same value
+  Uniform(Voxel),
+  /// Voxels are stored as indices into the palette, bit-packed into words.
+  /// Indices never span two words.
+  Paletted {
+    palette: Vec<Voxel>,
+    bits: u32,
+    data: Vec<u64>,
+  },
+}
+
+#[inline]
+fn bits_for(palette_len: usize) -> u32 {
+  let bits = usize::BITS - (palette_len.max(2) - 1).leading_zeros();
+  bits.max(1)
+}
+
+#[inline]
+fn words_for(bits: u32) -> usize {
+  let per_word = (u64::BITS / bits) as usize;
+  (SECTION_VOLUME + per_word - 1) / per_word
+}
+
+#[inline]
+fn read_index(data: &[u64], bits: u32, index: usize) -> usize {
+  let per_word = (u64::BITS / bits) as usize;
+  let shift = (index % per_word) as u32 * bits;
+  ((data[index / per_word] >> shift) & ((1 << bits) - 1)) as usize
+}
+
+#[inline]
+fn write_index(data: &mut [u64], bits: u32, index: usize, value: usize) {
+  let per_word = (u64::BITS / bits) as usize;
+  let shift = (index % per_word) as u32 * bits;
+  let mask = ((1u64 << bits) - 1) << shift;
+  let word = &mut data[index / per_word];
+  *word = (*word & !mask) | ((value as u64) << shift);
+}
+
+fn repack(data: &[u64], bits: u32, new_bits: u32) -> Vec<u64> {
+  let mut new_data = vec![0; words_for(new_bits)];
+  for index in 0..SECTION_VOLUME {
+    write_index(
+      &mut new_data,
+      new_bits,
+      index,
+      read_index(data, bits, index),
+    );
+  }
+  new_data
+}
+
+impl Section {
+  fn get(&self, index: usize) -> Voxel {
+    match self {
+      Section::Uniform(voxel) => *voxel,
+      Section::Paletted {
+        palette,
+        bits,
+        data,
+      } => palette[read_index(data, *bits, index)],
+    }
+  }
+
+  fn set(&mut self, index: usize, voxel: Voxel) {
+    if let Section::Uniform(current) = *self {
+      if current == voxel {
+        return;
+      }
+
+      *self = Section::Paletted {
+        palette: vec![current],
+        bits: 1,
+        data: vec![0; words_for(1)],
+      };
+    }
+
+    if let Section::Paletted {
+      palette,
+      bits,
+      data,
+    } = self
+    {
+      let palette_index = match palette.iter().position(|v| *v == voxel) {
+        Some(i) => i,
+        None => {
+          palette.push(voxel);
+          let required_bits = bits_for(palette.len());
+          if required_bits > *bits {
+            *data = repack(data, *bits, required_bits);
+            *bits = required_bits;
+          }
+          palette.len() - 1
+        }
+      };
+
+      write_index(data, *bits, index, palette_index);
+    }
+  }
+
+  /// Drops palette entries that are no longer referenced and collapses the
+  /// section to a single value when possible.
+  fn optimize(&mut self) {
+    let (palette, bits, data) = match self {
+      Section::Uniform(_) => return,
+      Section::Paletted {
+        palette,
+        bits,
+        data,
+      } => (palette, *bits, data),
+    };
+
+    let mut used = vec![false; palette.len()];
+    for index in 0..SECTION_VOLUME {
+      used[read_index(data, bits, index)] = true;
+    }
+
+    let used_count = used.iter().filter(|u| **u).count();
+    if used_count == 1 {
+      let voxel = palette[used.iter().position(|u| *u).unwrap()];
+      *self = Section::Uniform(voxel);
+      return;
+    }
+    if used_count == palette.len() {
+      return;
+    }
+
+    let mut remap = vec![0; palette.len()];
+    let mut new_palette = Vec::with_capacity(used_count);
+    for (i, voxel) in palette.iter().enumerate() {
+      if used[i] {
+        remap[i] = new_palette.len();
+        new_palette.push(*voxel);
+      }
+    }
+
+    let new_bits = bits_for(new_palette.len());
+    let mut new_data = vec![0; words_for(new_bits)];
+    for index in 0..SECTION_VOLUME {
+      write_index(
+        &mut new_data,
+        new_bits,
+        index,
+        remap[read_index(data, bits, index)],
+      );
+    }
+
+    *self = Section::Paletted {
+      palette: new_palette,
+      bits: new_bits,
+      data: new_data,
+    };
+  }
+
+  #[cfg(test)]
+  fn heap_size(&self) -> usize {
+    match self {
+      Section::Uniform(_) => 0,
+      Section::Paletted { palette, data, .. } => {
+        palette.capacity() * size_of::<Voxel>() + data.capacity() * size_of::<u64>()
+      }
+    }
+  }
+}
+
+/// Palette compressed voxels of the padded chunk extent.
+///
+/// The chunk is split into `SECTION_HEIGHT` tall sections, each with its own
+/// palette, so sections of air or solid rock take no space beyond the
+/// section header. Sections are aligned with the rendered chunk sections.
+#[derive(Clone, Debug)]
+pub struct ChunkVoxels {
+  sections: Vec<Section>,
+}
+
+impl ChunkVoxels {
+  pub fn new(voxel: Voxel) -> Self {
+    Self {
+      sections: vec![Section::Uniform(voxel); SECTION_COUNT],
+    }
+  }
+
+  /// Padded extent covered by the storage
+  #[inline]
+  pub fn extent() -> Extent3i {
+    chunk_extent().padded(1)
+  }
+
+  #[inline]
+  pub fn get(&self, p: Point3i) -> Voxel {
+    let (section, index) = locate(p);
+    self.sections[section].get(index)
+  }
+
+  #[inline]
+  pub fn set(&mut self, p: Point3i, voxel: Voxel) {
+    let (section, index) = locate(p);
+    self.sections[section].set(index, voxel);
+  }
+
+  pub fn fill_extent(&mut self, extent: &Extent3i, voxel: Voxel) {
+    let extent = extent.intersection(&Self::extent());
+    if extent.is_empty() {
+      return;
+    }
+
+    let shape = extent.shape.0;
+    let covers_section_plane = shape[0] == SIZE_X && shape[2] == SIZE_Z;
+
+    for p in extent.iter_points() {
+      let (section, index) = locate(p);
+
+      // Sections fully inside the extent become uniform on the first visit,
+      // the rest of their points is skipped
+      if covers_section_plane {
+        let section_min_y = stored_section_min_y(section);
+        let section_max_y = section_min_y + SECTION_HEIGHT - 1;
+        if extent.minimum.0[1] <= section_min_y && extent.max().0[1] >= section_max_y {
+          match self.sections[section] {
+            Section::Uniform(current) if current == voxel => {}
+            _ => self.sections[section] = Section::Uniform(voxel),
+          }
+          continue;
+        }
+      }
+
+      self.sections[section].set(index, voxel);
+    }
+  }
+
+  /// Compacts palettes after a batch of modifications, e.g. generation
+  pub fn optimize(&mut self) {
+    for section in self.sections.iter_mut() {
+      section.optimize();
+    }
+  }
+
+  /// Returns the voxel filling the whole section `section_y`, if there is
+  /// such. The padding around the section isn't considered.
+  pub fn uniform_section(&self, section_y: i32) -> Option<Voxel> {
+    match self.sections[(section_y + 1) as usize] {
+      Section::Uniform(voxel) => Some(voxel),
+      Section::Paletted { .. } => None,
+    }
+  }
+
+  /// Copies the outermost layer of voxels facing the neighbour at `side`,
+  /// one of the four horizontal unit offsets
+  pub fn border(&self, side: IVec2) -> Vec<Voxel> {
+    let coord = if side.x + side.y > 0 {
+      if side.x != 0 {
+        CHUNK_SIZE_X - 1
+      } else {
+        CHUNK_SIZE_Z - 1
+      }
+    } else {
+      0
+    };
+    side_layer(side, coord)
+      .iter_points()
+      .map(|p| self.get(p))
+      .collect()
+  }
+
+  /// Overwrites the padding layer towards the neighbour at `side` with the
+  /// neighbour's `border(-side)`
+  pub fn set_padding(&mut self, side: IVec2, voxels: &[Voxel]) {
+    for (p, voxel) in padding_layer(side).iter_points().zip(voxels.iter()) {
+      self.set(p, *voxel);
+    }
+  }
+
+  /// Empties the padding layer towards the neighbour at `side`
+  pub fn clear_padding(&mut self, side: IVec2) {
+    self.fill_extent(&padding_layer(side), Voxel::default());
+  }
+
+  /// Approximate number of bytes used by the storage
+  #[cfg(test)]
+  pub fn memory_usage(&self) -> usize {
+    size_of::<Self>()
+      + self.sections.capacity() * size_of::<Section>()
+      + self.sections.iter().map(Section::heap_size).sum::<usize>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{TerrainKind, WorldGenConfig};
+  use crate::world::chunk_generator::WorldGenerator;
+  use crate::world::BlockId;
+
+  fn voxel(id: u16) -> Voxel {
+    Voxel::new(BlockId(id))
+  }
+
+  fn section_bits(section: &Section) -> Option<u32> {
+    match section {
+      Section::Uniform(_) => None,
+      Section::Paletted { bits, .. } => Some(*bits),
+    }
+  }
+
+  #[test]
+  fn section_grows_index_width() {
+    let mut section = Section::Uniform(voxel(0));
+    assert_eq!(section_bits(&section), None);
+
+    // Palette sizes 2, 3 and 5 need 1, 2 and 3 bits
+    let expected_bits = [1, 2, 2, 3];
+    for (i, bits) in expected_bits.iter().enumerate() {
+      section.set(i + 1, voxel(i as u16 + 1));
+      assert_eq!(section_bits(&section), Some(*bits));
+    }
+
+    // Values written before repacking survive it
+    assert_eq!(section.get(0), voxel(0));
+    for i in 1..=expected_bits.len() {
+      assert_eq!(section.get(i), voxel(i as u16));
+    }
+    assert_eq!(section.get(SECTION_VOLUME - 1), voxel(0));
+  }
+
+  #[test]
+  fn optimize_collapses_to_uniform() {
+    let mut section = Section::Uniform(voxel(0));
+    section.set(7, voxel(1));
+    section.set(7, voxel(0));
+    section.optimize();
+    assert!(matches!(section, Section::Uniform(v) if v == voxel(0)));
+  }
+
+  #[test]
+  fn optimize_remaps_indices() {
+    let mut section = Section::Uniform(voxel(0));
+    for i in 1..5 {
+      section.set(i, voxel(i as u16));
+    }
+    assert_eq!(section_bits(&section), Some(3));
+
+    // Drop the background and two of the blocks from the palette
+    for index in 0..SECTION_VOLUME {
+      section.set(index, voxel(3));
+    }
+    section.set(10, voxel(4));
+    section.optimize();
+
+    match &section {
+      Section::Paletted { palette, bits, .. } => {
+        assert_eq!(palette, &vec![voxel(3), voxel(4)]);
+        assert_eq!(*bits, 1);
+      }
+      Section::Uniform(_) => panic!("section with two blocks collapsed"),
+    }
+    assert_eq!(section.get(10), voxel(4));
+    assert_eq!(section.get(0), voxel(3));
+    assert_eq!(section.get(SECTION_VOLUME - 1), voxel(3));
+  }
+
+  #[test]
+  fn fill_extent_makes_covered_sections_uniform() {
+    let mut voxels = ChunkVoxels::new(Voxel::default());
+    voxels.set(PointN([3, 20, 3]), voxel(2));
+
+    // Covers sections 1 and 2 completely and section 3 partially
+    let extent = Extent3i::from_min_and_shape(
+      PointN([-1, SECTION_HEIGHT, -1]),
+      PointN([SIZE_X, 2 * SECTION_HEIGHT + 1, SIZE_Z]),
+    );
+    voxels.fill_extent(&extent, voxel(1));
+
+    assert_eq!(voxels.uniform_section(1), Some(voxel(1)));
+    assert_eq!(voxels.uniform_section(2), Some(voxel(1)));
+    assert_eq!(voxels.uniform_section(3), None);
+    assert_eq!(voxels.uniform_section(0), Some(Voxel::default()));
+    assert_eq!(voxels.get(PointN([3, 20, 3])), voxel(1));
+    assert_eq!(voxels.get(PointN([0, 3 * SECTION_HEIGHT, 0])), voxel(1));
+    assert_eq!(
+      voxels.get(PointN([0, 3 * SECTION_HEIGHT + 1, 0])),
+      Voxel::default()
+    );
+  }
+
+  #[test]
+  fn clear_padding_keeps_chunk_voxels() {
+    let mut voxels = ChunkVoxels::new(voxel(1));
+    let side = IVec2::new(1, 0);
+    voxels.clear_padding(side);
+
+    assert_eq!(voxels.get(PointN([CHUNK_SIZE_X, 5, 3])), Voxel::default());
+    assert_eq!(voxels.get(PointN([CHUNK_SIZE_X - 1, 5, 3])), voxel(1));
+    assert_eq!(voxels.get(PointN([-1, 5, 3])), voxel(1));
+    assert!(voxels.border(side).iter().all(|v| *v == voxel(1)));
+  }
+
+  /// Bytes of a chunk in the layout the palette storage replaced, a dense
+  /// array of `[u8; 4]` voxels covering the padded 18x514x18 extent
+  const UNCOMPRESSED_CHUNK_BYTES: usize = 18 * 514 * 18 * 4;
+  /// Generated chunks are mostly uniform sections of air, stone and water,
+  /// so they take at most a quarter of the uncompressed size
+  const MIN_COMPRESSION_RATIO: usize = 4;
+
+  #[test]
+  fn generated_chunks_use_less_memory_than_uncompressed() {
+    assert_eq!(
+      ChunkVoxels::extent().num_points() * 4,
+      UNCOMPRESSED_CHUNK_BYTES
+    );
+
+    for terrain in [TerrainKind::Heightmap, TerrainKind::Density].iter() {
+      let config = WorldGenConfig {
+        terrain: *terrain,
+        ..Default::default()
+      };
+      let generator = WorldGenerator::from_config(&config);
+
+      let chunk_positions = [[0, 0], [-7, 3], [25, -40], [-120, -90]];
+      let mut total = 0;
+      for pos in chunk_positions.iter() {
+        let mut voxels = ChunkVoxels::new(Voxel::default());
+        generator
+          .0
+          .generate(IVec2::new(pos[0], pos[1]), &mut voxels);
+        voxels.optimize();
+        total += voxels.memory_usage();
+      }
+      let average = total / chunk_positions.len();
+
+      assert!(
+        average * MIN_COMPRESSION_RATIO <= UNCOMPRESSED_CHUNK_BYTES,
+        "{:?} chunks take {} bytes on average",
+        terrain,
+        average
+      );
+    }
+  }
+}
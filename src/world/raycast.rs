@@ -0,0 +1,261 @@
+use crate::world::{Chunk, ChunkState, Voxel, VoxelWorld};
+use bevy::math::{IVec3, Vec3};
+use bevy::prelude::Query;
+
+/// Voxel hit by a ray
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+  /// World coordinate of the hit voxel
+  pub voxel: IVec3,
+  /// Normal of the face the ray entered through, zero if the ray started
+  /// inside the voxel
+  pub normal: IVec3,
+  /// Distance from the origin to the hit face
+  pub distance: f32,
+}
+
+impl VoxelWorld {
+  /// Casts a ray through the loaded chunks and returns the first voxel
+  /// within `max_distance` accepted by `is_target`, e.g. the first solid
+  /// block. Voxels are traversed one by one (DDA), so
+  /// the ray can't skip corners. Chunks that aren't loaded or generated yet
+  /// are treated as air.
+  pub fn raycast(
+    &self,
+    chunks: &Query<(&Chunk, &ChunkState)>,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    is_target: impl Fn(Voxel) -> bool,
+  ) -> Option<RaycastHit> {
+    // Degenerate transforms produce rays that can't be traversed
+    if !origin.is_finite() || !direction.is_finite() {
+      return None;
+    }
+    let direction = direction.normalize_or_zero();
+    if direction == Vec3::ZERO {
+      return None;
+    }
+
+    let origin: [f32; 3] = origin.into();
+    let direction: [f32; 3] = direction.into();
+
+    let mut voxel = [0; 3];
+    let mut step = [0; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+    for axis in 0..3 {
+      voxel[axis] = origin[axis].floor() as i32;
+      if direction[axis] > 0.0 {
+        step[axis] = 1;
+        t_delta[axis] = 1.0 / direction[axis];
+        t_max[axis] = (voxel[axis] as f32 + 1.0 - origin[axis]) * t_delta[axis];
+      } else if direction[axis] < 0.0 {
+        step[axis] = -1;
+        t_delta[axis] = -1.0 / direction[axis];
+        t_max[axis] = (origin[axis] - voxel[axis] as f32) * t_delta[axis];
+      }
+    }
+
+    let mut normal = [0; 3];
+    let mut distance = 0.0;
+    loop {
+      let pos = IVec3::from(voxel);
+      if let Some(hit) = self.get_voxel(chunks, pos) {
+        if is_target(hit) {
+          return Some(RaycastHit {
+            voxel: pos,
+            normal: IVec3::from(normal),
+            distance,
+          });
+        }
+      }
+
+      // Step into the neighbour whose boundary the ray crosses first
+      let axis = (0..3)
+        .min_by(|a, b| t_max[*a].partial_cmp(&t_max[*b]).unwrap())
+        .unwrap();
+      if t_max[axis] > max_distance {
+        return None;
+      }
+
+      voxel[axis] += step[axis];
+      distance = t_max[axis];
+      t_max[axis] += t_delta[axis];
+      normal = [0; 3];
+      normal[axis] = -step[axis];
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world::{BlockId, BlockRegistry, ChunkPos, ChunkVoxels, LocalVoxelPos};
+  use bevy::math::IVec2;
+  use bevy::prelude::*;
+
+  const DIRT: Voxel = Voxel::new(BlockId::DIRT);
+
+  struct Ray {
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    hit: Option<RaycastHit>,
+  }
+
+  fn cast_ray(world: Res<VoxelWorld>, chunks: Query<(&Chunk, &ChunkState)>, mut ray: ResMut<Ray>) {
+    let registry = BlockRegistry::default();
+    ray.hit = world.raycast(
+      &chunks,
+      ray.origin,
+      ray.direction,
+      ray.max_distance,
+      |voxel| registry.get(voxel.block).solid,
+    );
+  }
+
+  /// World with generated chunks at `positions`, empty but for `blocks`
+  fn build_world(positions: &[IVec2], blocks: &[(IVec3, Voxel)]) -> World {
+    let mut world = World::new();
+    let mut voxel_world = VoxelWorld::default();
+
+    for pos in positions.iter() {
+      let mut block_data = ChunkVoxels::new(Voxel::default());
+      for (voxel_pos, voxel) in blocks.iter() {
+        let (ChunkPos(chunk_pos), local) = LocalVoxelPos::split(*voxel_pos).unwrap();
+        if chunk_pos == *pos {
+          block_data.set(local.point(), *voxel);
+        }
+      }
+
+      let entity = world
+        .spawn()
+        .insert(Chunk {
+          pos: *pos,
+          block_data,
+          modified: false,
+        })
+        .insert(ChunkState::Ready)
+        .id();
+      voxel_world.loaded_chunks.insert(*pos, entity);
+    }
+
+    world.insert_resource(voxel_world);
+    world
+  }
+
+  fn cast(
+    world: &mut World,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+  ) -> Option<RaycastHit> {
+    world.insert_resource(Ray {
+      origin,
+      direction,
+      max_distance,
+      hit: None,
+    });
+    SystemStage::single(cast_ray.system()).run(world);
+    world.get_resource::<Ray>().unwrap().hit
+  }
+
+  fn chunks_around_origin() -> Vec<IVec2> {
+    let mut positions = Vec::new();
+    for x in -1..=1 {
+      for z in -1..=1 {
+        positions.push(IVec2::new(x, z));
+      }
+    }
+    positions
+  }
+
+  #[test]
+  fn axis_aligned_hit() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(6, 10, 2), DIRT)]);
+    let hit = cast(&mut world, Vec3::new(2.5, 10.5, 2.5), Vec3::X, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(6, 10, 2));
+    assert_eq!(hit.normal, IVec3::new(-1, 0, 0));
+    assert!((hit.distance - 3.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn diagonal_hit() {
+    let wall: Vec<(IVec3, Voxel)> = (0..20).map(|y| (IVec3::new(4, y, 0), DIRT)).collect();
+    let mut world = build_world(&chunks_around_origin(), &wall);
+
+    // Crosses x = 4 at y = 12.25, after 1.75 steps of `direction`
+    let direction = Vec3::new(2.0, 1.0, 0.0);
+    let hit = cast(&mut world, Vec3::new(0.5, 10.5, 0.5), direction, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(4, 12, 0));
+    assert_eq!(hit.normal, IVec3::new(-1, 0, 0));
+    assert!((hit.distance - 1.75 * direction.length()).abs() < 1e-4);
+  }
+
+  #[test]
+  fn hit_across_chunk_border() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(17, 10, 3), DIRT)]);
+    let hit = cast(&mut world, Vec3::new(14.5, 10.5, 3.5), Vec3::X, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(17, 10, 3));
+    assert_eq!(hit.normal, IVec3::new(-1, 0, 0));
+    assert!((hit.distance - 2.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn hit_in_negative_coordinates() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(-1, 10, -5), DIRT)]);
+    let hit = cast(&mut world, Vec3::new(-0.5, 10.5, -0.5), -Vec3::Z, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(-1, 10, -5));
+    assert_eq!(hit.normal, IVec3::new(0, 0, 1));
+    assert!((hit.distance - 3.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn ray_starting_inside_solid_voxel() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(6, 10, 2), DIRT)]);
+    let hit = cast(&mut world, Vec3::new(6.5, 10.5, 2.5), Vec3::Y, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(6, 10, 2));
+    assert_eq!(hit.normal, IVec3::ZERO);
+    assert_eq!(hit.distance, 0.0);
+  }
+
+  #[test]
+  fn miss_beyond_max_distance() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(6, 10, 2), DIRT)]);
+    assert_eq!(
+      cast(&mut world, Vec3::new(2.5, 10.5, 2.5), Vec3::X, 3.0),
+      None
+    );
+    assert!(cast(&mut world, Vec3::new(2.5, 10.5, 2.5), Vec3::X, 4.0).is_some());
+  }
+
+  #[test]
+  fn non_finite_ray_misses() {
+    let mut world = build_world(&chunks_around_origin(), &[(IVec3::new(6, 10, 2), DIRT)]);
+    let origin = Vec3::new(2.5, 10.5, 2.5);
+    assert_eq!(
+      cast(&mut world, origin, Vec3::new(f32::NAN, 0.0, 0.0), 10.0),
+      None
+    );
+    assert_eq!(cast(&mut world, Vec3::splat(f32::NAN), Vec3::X, 10.0), None);
+    assert_eq!(
+      cast(&mut world, origin, Vec3::new(f32::INFINITY, 0.0, 0.0), 10.0),
+      None
+    );
+  }
+
+  #[test]
+  fn ray_passes_through_water() {
+    let water = Voxel::new(BlockId::WATER);
+    let blocks = [
+      (IVec3::new(3, 10, 2), water),
+      (IVec3::new(4, 10, 2), water),
+      (IVec3::new(5, 10, 2), DIRT),
+    ];
+    let mut world = build_world(&chunks_around_origin(), &blocks);
+    let hit = cast(&mut world, Vec3::new(2.5, 10.5, 2.5), Vec3::X, 10.0).unwrap();
+    assert_eq!(hit.voxel, IVec3::new(5, 10, 2));
+    assert_eq!(hit.normal, IVec3::new(-1, 0, 0));
+  }
+}
@@ -0,0 +1,201 @@
+use crate::config::ChunkStreamingConfig;
+use bevy::core::Time;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::ecs::system::{Res, ResMut};
+use std::time::Duration;
+
+/// Number of queued generation requests
+pub const DIAGNOSTIC_GENERATION_QUEUE: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414403);
+/// Chunks generated (or loaded from disk) per second
+pub const DIAGNOSTIC_GENERATION_THROUGHPUT: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414404);
+/// Average time it takes to generate a chunk, in milliseconds
+pub const DIAGNOSTIC_GENERATION_COST: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414405);
+/// Number of sections queued for meshing
+pub const DIAGNOSTIC_MESHING_QUEUE: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414406);
+/// Sections meshed per second
+pub const DIAGNOSTIC_MESHING_THROUGHPUT: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414407);
+/// Average time it takes to mesh a section, in milliseconds
+pub const DIAGNOSTIC_MESHING_COST: DiagnosticId =
+  DiagnosticId::from_u128(143958725863147716307145338307853414408);
+
+/// Cost assumed for a work item until the first one is measured
+const INITIAL_COST: f64 = 0.002;
+/// Weight of a new measurement in the moving average of the cost
+const COST_SMOOTHING: f64 = 0.1;
+
+/// Kinds of chunk work sharing the frame budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkWork {
+  Generation,
+  Meshing,
+}
+
+const WORK_KINDS: usize = 2;
+
+impl ChunkWork {
+  #[inline]
+  fn index(self) -> usize {
+    match self {
+      ChunkWork::Generation => 0,
+      ChunkWork::Meshing => 1,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WorkQueue {
+  len: usize,
+  /// Moving average of the measured cost of an item, in seconds
+  cost: f64,
+  /// Time that may still be spent on this kind of work in the current frame
+  allotment: f64,
+  completed: usize,
+}
+
+impl Default for WorkQueue {
+  fn default() -> Self {
+    Self {
+      len: 0,
+      cost: INITIAL_COST,
+      allotment: 0.0,
+      completed: 0,
+    }
+  }
+}
+
+/// Limits how much chunk work is started per frame.
+///
+/// Every frame, `ChunkStreamingConfig::work_budget_ms` is split between the
+/// work queues in proportion to their estimated backlog (queue length times
+/// the measured cost per item). Non-empty queues always get at least one
+/// item, so no kind of work starves. Systems dispatching work ask
+/// `has_budget` before starting an item, `spend` when they do, and `record`
+/// the measured duration once it is done.
+#[derive(Debug, Default)]
+pub struct ChunkWorkScheduler {
+  queues: [WorkQueue; WORK_KINDS],
+}
+
+impl ChunkWorkScheduler {
+  #[inline]
+  pub fn has_budget(&self, work: ChunkWork) -> bool {
+    self.queues[work.index()].allotment > 0.0
+  }
+
+  /// Charges the estimated cost of an item started this frame
+  #[inline]
+  pub fn spend(&mut self, work: ChunkWork) {
+    let queue = &mut self.queues[work.index()];
+    queue.allotment -= queue.cost;
+  }
+
+  /// Charges work done on the main thread by its measured duration
+  #[inline]
+  pub fn spend_measured(&mut self, work: ChunkWork, duration: Duration) {
+    self.queues[work.index()].allotment -= duration.as_secs_f64();
+  }
+
+  /// Reports the number of items left in a queue after dispatching
+  #[inline]
+  pub fn set_queue_len(&mut self, work: ChunkWork, len: usize) {
+    self.queues[work.index()].len = len;
+  }
+
+  /// Records the measured duration of a finished item
+  pub fn record(&mut self, work: ChunkWork, duration: Duration) {
+    let queue = &mut self.queues[work.index()];
+    queue.cost += (duration.as_secs_f64() - queue.cost) * COST_SMOOTHING;
+    queue.completed += 1;
+  }
+
+  fn plan_frame(&mut self, budget: f64) {
+    let demand: f64 = self.queues.iter().map(|q| q.len as f64 * q.cost).sum();
+    let share = if demand > budget {
+      budget / demand
+    } else {
+      1.0
+    };
+
+    for queue in self.queues.iter_mut() {
+      queue.allotment = queue.len as f64 * queue.cost * share;
+      if queue.len > 0 {
+        queue.allotment = queue.allotment.max(queue.cost);
+      }
+    }
+  }
+}
+
+pub(crate) fn setup_scheduler_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_GENERATION_QUEUE,
+    "chunk_generation_queue",
+    20,
+  ));
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_GENERATION_THROUGHPUT,
+    "chunk_generation_per_second",
+    20,
+  ));
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_GENERATION_COST,
+    "chunk_generation_ms",
+    20,
+  ));
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_MESHING_QUEUE,
+    "section_meshing_queue",
+    20,
+  ));
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_MESHING_THROUGHPUT,
+    "section_meshing_per_second",
+    20,
+  ));
+  diagnostics.add(Diagnostic::new(
+    DIAGNOSTIC_MESHING_COST,
+    "section_meshing_ms",
+    20,
+  ));
+}
+
+/// Reports the last frame's work to `Diagnostics` and splits the budget for
+/// the upcoming frame. Runs at the start of every frame.
+pub(crate) fn plan_chunk_work(
+  time: Res<Time>,
+  config: Res<ChunkStreamingConfig>,
+  mut scheduler: ResMut<ChunkWorkScheduler>,
+  mut diagnostics: ResMut<Diagnostics>,
+) {
+  let delta = time.delta_seconds_f64();
+  let measurements = [
+    (
+      ChunkWork::Generation,
+      DIAGNOSTIC_GENERATION_QUEUE,
+      DIAGNOSTIC_GENERATION_THROUGHPUT,
+      DIAGNOSTIC_GENERATION_COST,
+    ),
+    (
+      ChunkWork::Meshing,
+      DIAGNOSTIC_MESHING_QUEUE,
+      DIAGNOSTIC_MESHING_THROUGHPUT,
+      DIAGNOSTIC_MESHING_COST,
+    ),
+  ];
+
+  for (work, queue_id, throughput_id, cost_id) in measurements.iter() {
+    let queue = &mut scheduler.queues[work.index()];
+    diagnostics.add_measurement(*queue_id, queue.len as f64);
+    if delta > 0.0 {
+      diagnostics.add_measurement(*throughput_id, queue.completed as f64 / delta);
+    }
+    diagnostics.add_measurement(*cost_id, queue.cost * 1000.0);
+    queue.completed = 0;
+  }
+
+  scheduler.plan_frame(config.work_budget_ms as f64 / 1000.0);
+}
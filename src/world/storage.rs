@@ -0,0 +1,311 @@
+use crate::config::WorldGenConfig;
+use crate::world::palette::ChunkVoxels;
+use crate::world::{chunk_extent, BlockId, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const SAVE_DIR: &str = "saves";
+/// Generator settings the world was created with, stored next to the
+/// region files
+const METADATA_FILE: &str = "world.ron";
+
+/// Number of chunks along each axis stored in a single region file
+pub const REGION_SIZE: i32 = 32;
+
+/// Each header entry holds offset (u64) and length (u32) of a chunk blob
+const HEADER_ENTRY_SIZE: u64 = 12;
+const HEADER_SIZE: u64 = (REGION_SIZE * REGION_SIZE) as u64 * HEADER_ENTRY_SIZE;
+
+const VOXEL_BYTES: usize = 2;
+const CHUNK_BYTES: usize = (CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z) as usize * VOXEL_BYTES;
+
+/// 64 bit FNV-1a hash, which unlike `DefaultHasher` is stable between runs
+/// and Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+  })
+}
+
+/// Stores chunks in region files of `REGION_SIZE`x`REGION_SIZE` chunks.
+///
+/// A region file starts with a header table holding the location of every
+/// chunk in the file, followed by zlib compressed chunk blobs. A saved chunk
+/// is rewritten in place when it fits into the space of its previous blob,
+/// otherwise a new blob is appended and the old space is left unused.
+#[derive(Clone)]
+pub struct RegionStorage {
+  dir: PathBuf,
+  // Serializes access to region files between the main thread and
+  // generation tasks
+  lock: Arc<Mutex<()>>,
+}
+
+impl RegionStorage {
+  pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+    Self {
+      dir: dir.into(),
+      lock: Arc::new(Mutex::new(())),
+    }
+  }
+
+  /// Opens the save of the world generated with `config`. Every combination
+  /// of generator settings gets its own directory, named after the seed and
+  /// a hash of the settings, so changing `world_gen.ron` starts a new world
+  /// instead of mixing chunks of two different terrains.
+  pub fn for_world(config: &WorldGenConfig) -> io::Result<Self> {
+    Self::for_world_in(Path::new(SAVE_DIR), config)
+  }
+
+  fn for_world_in(root: &Path, config: &WorldGenConfig) -> io::Result<Self> {
+    let settings = ron::ser::to_string(config)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let dir = root.join(format!(
+      "world-{}-{:016x}",
+      config.seed,
+      fnv1a(settings.as_bytes())
+    ));
+
+    let storage = Self::new(dir);
+    storage.check_metadata(config)?;
+    Ok(storage)
+  }
+
+  /// Writes `config` as the world metadata if there is none yet, otherwise
+  /// compares it with the stored one
+  fn check_metadata(&self, config: &WorldGenConfig) -> io::Result<()> {
+    let path = self.dir.join(METADATA_FILE);
+    match File::open(&path) {
+      Ok(file) => {
+        let saved: WorldGenConfig = ron::de::from_reader(file)
+          .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        if saved != *config {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+              "world in {:?} was generated with different settings: {:?}",
+              self.dir, saved
+            ),
+          ));
+        }
+        Ok(())
+      }
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        let metadata = ron::ser::to_string_pretty(config, Default::default())
+          .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, metadata)
+      }
+      Err(err) => Err(err),
+    }
+  }
+
+  fn region_path(&self, chunk_pos: IVec2) -> PathBuf {
+    let region_x = chunk_pos.x.div_euclid(REGION_SIZE);
+    let region_z = chunk_pos.y.div_euclid(REGION_SIZE);
+    self.dir.join(format!("r.{}.{}.region", region_x, region_z))
+  }
+
+  fn header_offset(chunk_pos: IVec2) -> u64 {
+    let local_x = chunk_pos.x.rem_euclid(REGION_SIZE);
+    let local_z = chunk_pos.y.rem_euclid(REGION_SIZE);
+    (local_z * REGION_SIZE + local_x) as u64 * HEADER_ENTRY_SIZE
+  }
+
+  /// Offset and length of the chunk blob, a length of 0 means the chunk
+  /// hasn't been saved
+  fn read_header_entry(file: &mut File, chunk_pos: IVec2) -> io::Result<(u64, u32)> {
+    let mut entry = [0u8; HEADER_ENTRY_SIZE as usize];
+    file.seek(SeekFrom::Start(Self::header_offset(chunk_pos)))?;
+    file.read_exact(&mut entry)?;
+
+    let offset = u64::from_le_bytes([
+      entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7],
+    ]);
+    let length = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+    Ok((offset, length))
+  }
+
+  /// Loads a previously saved chunk into `voxels`.
+  /// Returns `Ok(false)` when the chunk hasn't been saved yet.
+  pub fn load(&self, chunk_pos: IVec2, voxels: &mut ChunkVoxels) -> io::Result<bool> {
+    let _guard = self.lock.lock().unwrap();
+
+    let mut file = match File::open(self.region_path(chunk_pos)) {
+      Ok(file) => file,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+      Err(err) => return Err(err),
+    };
+
+    let (offset, length) = Self::read_header_entry(&mut file, chunk_pos)?;
+    if length == 0 {
+      return Ok(false);
+    }
+
+    let mut compressed = vec![0u8; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut compressed)?;
+
+    let mut bytes = Vec::with_capacity(CHUNK_BYTES);
+    ZlibDecoder::new(&compressed[..]).read_to_end(&mut bytes)?;
+    if bytes.len() != CHUNK_BYTES {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "chunk {:?} has {} bytes, expected {}",
+          chunk_pos,
+          bytes.len(),
+          CHUNK_BYTES
+        ),
+      ));
+    }
+
+    let mut voxel_bytes = bytes.chunks_exact(VOXEL_BYTES);
+    for p in chunk_extent().iter_points() {
+      let block = voxel_bytes.next().unwrap();
+      voxels.set(
+        p,
+        Voxel::new(BlockId(u16::from_le_bytes([block[0], block[1]]))),
+      );
+    }
+
+    voxels.optimize();
+
+    Ok(true)
+  }
+
+  pub fn save(&self, chunk_pos: IVec2, voxels: &ChunkVoxels) -> io::Result<()> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for p in chunk_extent().iter_points() {
+      encoder.write_all(&voxels.get(p).block.0.to_le_bytes())?;
+    }
+    let compressed = encoder.finish()?;
+
+    let _guard = self.lock.lock().unwrap();
+
+    fs::create_dir_all(&self.dir)?;
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .open(self.region_path(chunk_pos))?;
+
+    if file.metadata()?.len() < HEADER_SIZE {
+      file.set_len(HEADER_SIZE)?;
+    }
+
+    // Blobs only shrink in place, a blob that doesn't fit anymore moves to
+    // the end of the file
+    let (old_offset, old_length) = Self::read_header_entry(&mut file, chunk_pos)?;
+    let offset = if old_length > 0 && compressed.len() <= old_length as usize {
+      file.seek(SeekFrom::Start(old_offset))?
+    } else {
+      file.seek(SeekFrom::End(0))?
+    };
+    file.write_all(&compressed)?;
+
+    file.seek(SeekFrom::Start(Self::header_offset(chunk_pos)))?;
+    file.write_all(&offset.to_le_bytes())?;
+    file.write_all(&(compressed.len() as u32).to_le_bytes())?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use building_blocks::core::PointN;
+
+  fn temp_storage(name: &str) -> RegionStorage {
+    let dir = std::env::temp_dir().join(format!("region-storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    RegionStorage::new(dir)
+  }
+
+  fn region_len(storage: &RegionStorage, chunk_pos: IVec2) -> u64 {
+    fs::metadata(storage.region_path(chunk_pos)).unwrap().len()
+  }
+
+  #[test]
+  fn saved_chunk_round_trips() {
+    let storage = temp_storage("round-trip");
+    let chunk_pos = IVec2::new(-3, 5);
+    let mut voxels = ChunkVoxels::new(Voxel::default());
+    voxels.set(PointN([1, 2, 3]), Voxel::new(BlockId::DIRT));
+
+    storage.save(chunk_pos, &voxels).unwrap();
+    let mut loaded = ChunkVoxels::new(Voxel::default());
+    assert!(storage.load(chunk_pos, &mut loaded).unwrap());
+    assert_eq!(loaded.get(PointN([1, 2, 3])), Voxel::new(BlockId::DIRT));
+    assert_eq!(loaded.get(PointN([0, 0, 0])), Voxel::default());
+
+    assert!(!storage
+      .load(chunk_pos + IVec2::new(1, 0), &mut loaded)
+      .unwrap());
+  }
+
+  #[test]
+  fn resaving_reuses_blob_space() {
+    let storage = temp_storage("in-place");
+    let chunk_pos = IVec2::new(0, 0);
+    let mut voxels = ChunkVoxels::new(Voxel::default());
+    voxels.set(PointN([4, 4, 4]), Voxel::new(BlockId::DIRT));
+
+    storage.save(chunk_pos, &voxels).unwrap();
+    let len = region_len(&storage, chunk_pos);
+    for _ in 0..3 {
+      storage.save(chunk_pos, &voxels).unwrap();
+    }
+    assert_eq!(region_len(&storage, chunk_pos), len);
+
+    // A smaller blob fits into the old space as well
+    storage
+      .save(chunk_pos, &ChunkVoxels::new(Voxel::default()))
+      .unwrap();
+    assert_eq!(region_len(&storage, chunk_pos), len);
+
+    let mut loaded = ChunkVoxels::new(Voxel::new(BlockId::DIRT));
+    assert!(storage.load(chunk_pos, &mut loaded).unwrap());
+    assert_eq!(loaded.get(PointN([4, 4, 4])), Voxel::default());
+  }
+
+  #[test]
+  fn world_settings_get_their_own_directory() {
+    let root = std::env::temp_dir().join(format!("region-storage-worlds-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let config = WorldGenConfig::default();
+    let other = WorldGenConfig {
+      sea_level: config.sea_level + 10.0,
+      ..config.clone()
+    };
+
+    let storage = RegionStorage::for_world_in(&root, &config).unwrap();
+    let other_storage = RegionStorage::for_world_in(&root, &other).unwrap();
+    assert_ne!(storage.dir, other_storage.dir);
+    assert_eq!(
+      RegionStorage::for_world_in(&root, &config).unwrap().dir,
+      storage.dir
+    );
+  }
+
+  #[test]
+  fn metadata_mismatch_is_refused() {
+    let storage = temp_storage("metadata");
+    let config = WorldGenConfig::default();
+    storage.check_metadata(&config).unwrap();
+    storage.check_metadata(&config).unwrap();
+
+    let other = WorldGenConfig {
+      amplitude: config.amplitude * 2.0,
+      ..config
+    };
+    assert!(storage.check_metadata(&other).is_err());
+  }
+}
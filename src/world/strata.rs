@@ -0,0 +1,142 @@
+use crate::config::WorldGenConfig;
+use crate::world::biome::ColumnBiome;
+use crate::world::palette::ChunkVoxels;
+use crate::world::{BlockId, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use bevy::math::IVec2;
+use building_blocks::core::PointN;
+use simdnoise::NoiseBuilder;
+
+const STRATA_SEED_OFFSET: i32 = 6;
+const STRATA_NOISE_FREQUENCY: f32 = 0.05;
+
+/// Columns with surface this close to the sea level are covered with sand
+const BEACH_HEIGHT: i32 = 2;
+
+const TOPSOIL_DEPTH: f32 = 1.0;
+const DIRT_DEPTH: f32 = 4.0;
+
+/// Height ranges of the rock layers before noise perturbation
+const BEDROCK_MAX_Y: f32 = 1.0;
+const BASALT_MAX_Y: f32 = 40.0;
+const SLATE_MAX_Y: f32 = 80.0;
+
+/// How many voxels can the noise move each layer boundary
+const BOUNDARY_PERTURBATION: f32 = 6.0;
+const BEDROCK_PERTURBATION: f32 = 2.0;
+
+/// Material layers beneath the terrain surface, from top to bottom
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stratum {
+  /// Uppermost layer, uses the biome surface material
+  Topsoil,
+  /// Uses the biome subsurface material
+  Dirt,
+  /// Replaces topsoil and dirt on the shore
+  Sand,
+  Stone,
+  Slate,
+  Basalt,
+  Bedrock,
+}
+
+impl Stratum {
+  pub fn voxel(&self, biome: &ColumnBiome) -> Voxel {
+    match self {
+      Stratum::Topsoil => biome.dominant.params().surface,
+      Stratum::Dirt => biome.dominant.params().subsurface,
+      Stratum::Sand => Voxel::new(BlockId::BEACH_SAND),
+      Stratum::Stone => Voxel::new(BlockId::STONE),
+      Stratum::Slate => Voxel::new(BlockId::SLATE),
+      Stratum::Basalt => Voxel::new(BlockId::BASALT),
+      Stratum::Bedrock => Voxel::new(BlockId::BEDROCK),
+    }
+  }
+}
+
+/// Boundaries of the strata of a single column
+struct ColumnStrata {
+  topsoil_depth: i32,
+  dirt_depth: i32,
+  bedrock_max_y: i32,
+  basalt_max_y: i32,
+  slate_max_y: i32,
+}
+
+impl ColumnStrata {
+  fn new(noise: f32) -> Self {
+    // Noise is roughly in [-1.0, 1.0], map it to [0.0, 1.0] for thicknesses
+    let positive = (noise + 1.0) * 0.5;
+
+    Self {
+      topsoil_depth: (TOPSOIL_DEPTH + positive).round() as i32,
+      dirt_depth: (DIRT_DEPTH + positive * 3.0).round() as i32,
+      bedrock_max_y: (BEDROCK_MAX_Y + positive * BEDROCK_PERTURBATION).round() as i32,
+      basalt_max_y: (BASALT_MAX_Y + noise * BOUNDARY_PERTURBATION).round() as i32,
+      slate_max_y: (SLATE_MAX_Y - noise * BOUNDARY_PERTURBATION).round() as i32,
+    }
+  }
+
+  /// `depth` is the distance below the topmost solid voxel of the column
+  fn stratum_at(&self, y: i32, depth: i32, is_beach: bool) -> Stratum {
+    if y <= self.bedrock_max_y {
+      Stratum::Bedrock
+    } else if is_beach && depth < self.topsoil_depth + self.dirt_depth {
+      Stratum::Sand
+    } else if depth < self.topsoil_depth {
+      Stratum::Topsoil
+    } else if depth < self.topsoil_depth + self.dirt_depth {
+      Stratum::Dirt
+    } else if y <= self.basalt_max_y {
+      Stratum::Basalt
+    } else if y <= self.slate_max_y {
+      Stratum::Slate
+    } else {
+      Stratum::Stone
+    }
+  }
+}
+
+/// Replaces materials of all solid voxels in the chunk with strata based on
+/// their depth under the column surface. Air is left untouched, so the pass
+/// works for both heightmap and density terrain. Has to run before the sea is
+/// filled in, as water counts as non-empty.
+pub(crate) fn apply_strata(
+  chunk_pos: IVec2,
+  config: &WorldGenConfig,
+  biomes: &[ColumnBiome],
+  voxels: &mut ChunkVoxels,
+) {
+  let sea_level = config.sea_level.round() as i32;
+  let (noise, _, _) = NoiseBuilder::gradient_2d_offset(
+    (chunk_pos.x * CHUNK_SIZE_X) as f32,
+    CHUNK_SIZE_X as usize,
+    (chunk_pos.y * CHUNK_SIZE_Z) as f32,
+    CHUNK_SIZE_Z as usize,
+  )
+  .with_seed(config.seed.wrapping_add(STRATA_SEED_OFFSET))
+  .with_freq(STRATA_NOISE_FREQUENCY)
+  .generate();
+
+  for z in 0..CHUNK_SIZE_Z {
+    for x in 0..CHUNK_SIZE_X {
+      let column = (z * CHUNK_SIZE_X + x) as usize;
+      let strata = ColumnStrata::new(noise[column].max(-1.0).min(1.0));
+      let biome = &biomes[column];
+
+      let mut surface_y = None;
+      for y in (0..CHUNK_SIZE_Y).rev() {
+        let p = PointN([x, y, z]);
+        if voxels.get(p).is_air() {
+          continue;
+        }
+
+        let surface_y = *surface_y.get_or_insert(y);
+        let is_beach = (surface_y - sea_level).abs() <= BEACH_HEIGHT;
+        voxels.set(
+          p,
+          strata.stratum_at(y, surface_y - y, is_beach).voxel(biome),
+        );
+      }
+    }
+  }
+}
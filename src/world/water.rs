@@ -0,0 +1,24 @@
+use crate::world::palette::ChunkVoxels;
+use crate::world::{BlockId, Voxel, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use building_blocks::core::PointN;
+
+pub const WATER_VOXEL: Voxel = Voxel::new(BlockId::WATER);
+
+/// Fills air in every column from `sea_level` down to the first solid voxel.
+/// Air enclosed beneath terrain (e.g. caves) stays dry.
+pub(crate) fn fill_sea(voxels: &mut ChunkVoxels, sea_level: f32) {
+  let sea_level = (sea_level.round() as i32).min(CHUNK_SIZE_Y - 1);
+
+  for z in 0..CHUNK_SIZE_Z {
+    for x in 0..CHUNK_SIZE_X {
+      for y in (0..=sea_level).rev() {
+        let p = PointN([x, y, z]);
+        if !voxels.get(p).is_air() {
+          break;
+        }
+
+        voxels.set(p, WATER_VOXEL);
+      }
+    }
+  }
+}
@@ -1,31 +1,58 @@
-use crate::config::PlayerConfig;
-use crate::player::{Player, PlayerCamera};
-use crate::world::chunk_generator::generate_chunk;
-use crate::world::{CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
-use bevy::app::{App, Plugin};
+use crate::config::{ChunkStreamingConfig, WorldGenConfig, LOD_LEVELS};
+use crate::player::PlayerCamera;
+use crate::world::block::{
+  load_block_definitions, update_block_registry, BlockDefinitions, BlockDefinitionsLoader,
+};
+use crate::world::chunk_cache::ChunkCache;
+use crate::world::chunk_generator::WorldGenerator;
+use crate::world::chunk_state::{ChunkState, ChunkStateChanged};
+use crate::world::load_queue::{prioritise_chunk_loads, ChunkLoadQueue};
+use crate::world::palette::{ChunkVoxels, SECTIONS_PER_CHUNK, SECTION_HEIGHT};
+use crate::world::scheduler::{
+  plan_chunk_work, setup_scheduler_diagnostics, ChunkWork, ChunkWorkScheduler,
+};
+use crate::world::storage::RegionStorage;
+use crate::world::{
+  BlockId, BlockRegistry, ChunkPos, LocalVoxelPos, WorldPos, CHUNK_SIZE_X, CHUNK_SIZE_Y,
+  CHUNK_SIZE_Z,
+};
+use bevy::app::{App, AppExit, Plugin};
 use bevy::asset::Assets;
 use bevy::ecs::entity::Entity;
 use bevy::ecs::event::EventWriter;
 use bevy::ecs::system::Commands;
-use bevy::math::{IVec2, Vec3};
+use bevy::math::{IVec2, IVec3, Vec2, Vec3};
 use bevy::pbr::PbrBundle;
 use bevy::prelude::*;
 use bevy::prelude::{shape, Mesh, Query, Res, ResMut, StandardMaterial, Transform};
 use bevy::prelude::{Color, IntoSystem};
 use bevy::reflect::List;
-use bevy::utils::HashMap;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::{HashMap, HashSet};
 use building_blocks::core::{Extent3i, PointN};
-use building_blocks::prelude::Array3x1;
 use building_blocks::prelude::FillExtent;
+use futures_lite::future;
 use ndarray::Array3;
 use noise::{NoiseFn, OpenSimplex};
-use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 const VOXEL_SIZE: f32 = 0.25;
 const STEP_SIZE: f32 = 1.0 / VOXEL_SIZE;
 const NOISE_SCALE: f32 = 16.0 / VOXEL_SIZE;
 
+/// Edge length, in voxels, of the cells of the coarsest level of detail.
+/// Meshes read this many voxels of the neighbouring sections into their
+/// padding.
+const MAX_LOD_SCALE: i32 = 1 << LOD_LEVELS;
+
+/// Most chunks restored from the `ChunkCache` per frame. Restored chunks are
+/// synced with their neighbours and remeshed along with them right away, so
+/// restores are capped on top of the time they take.
+const MAX_CACHE_RESTORES_PER_FRAME: usize = 4;
+
 pub type ChunkMap = HashMap<IVec2, Entity>;
+/// Section entities keyed by `(chunk x, section y, chunk z)`
+pub type SectionMap = HashMap<IVec3, Entity>;
 
 #[derive(Clone, Copy, Hash, Debug, PartialEq, Eq, StageLabel)]
 pub enum WorldUpdateStage {
@@ -42,35 +69,231 @@ pub fn chunk_extent() -> Extent3i {
   );
 }
 
+/// Extent of a single chunk section in section-local coordinates
+#[inline]
+pub fn section_extent() -> Extent3i {
+  Extent3i::from_min_and_shape(
+    PointN([0; 3]),
+    PointN([CHUNK_SIZE_X, SECTION_HEIGHT, CHUNK_SIZE_Z]),
+  )
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Voxel {
-  pub attributes: [u8; 4],
+  pub block: BlockId,
+}
+
+impl Voxel {
+  pub const fn new(block: BlockId) -> Self {
+    Self { block }
+  }
+
+  #[inline]
+  pub fn is_air(&self) -> bool {
+    self.block == BlockId::AIR
+  }
 }
 
 struct ChunkSpawnRequest(IVec2);
 struct ChunkDespawnRequest(IVec2, Entity);
-struct ChunkLoadRequest(Entity);
 
-pub struct ChunkReadyEvent(pub IVec2, pub Entity);
+/// Requests remeshing of a single section, e.g. after a voxel in it changed
+pub struct RemeshSectionRequest(pub IVec3);
 
 #[derive(Default)]
 pub struct VoxelWorld {
   pub loaded_chunks: ChunkMap,
+  pub loaded_sections: SectionMap,
 }
 
-#[derive(Component, Debug)]
-pub enum ChunkLoadState {
-  Load,
-  Unload,
-  Despawn,
-  Generate,
-  Done,
+impl VoxelWorld {
+  /// Voxel at a world coordinate, `None` if its chunk isn't loaded or its
+  /// voxels aren't available yet
+  pub fn get_voxel(&self, chunks: &Query<(&Chunk, &ChunkState)>, pos: IVec3) -> Option<Voxel> {
+    let (chunk_pos, local) = LocalVoxelPos::split(pos)?;
+    let (chunk, state) = chunks.get(*self.loaded_chunks.get(&chunk_pos.0)?).ok()?;
+    if !state.has_voxels() {
+      return None;
+    }
+    Some(chunk.block_data.get(local.point()))
+  }
+
+  /// Replaces the voxel at a world coordinate and requests remeshing of its
+  /// section, plus the neighbouring sections when the voxel lies within
+  /// `MAX_LOD_SCALE` of their border. Returns the previous voxel, or `None`
+  /// if the chunk isn't loaded or its voxels aren't available yet, in which
+  /// case nothing is changed.
+  pub fn set_voxel(
+    &self,
+    chunks: &mut Query<(&mut Chunk, &ChunkState)>,
+    remesh_requests: &mut EventWriter<RemeshSectionRequest>,
+    pos: IVec3,
+    voxel: Voxel,
+  ) -> Option<Voxel> {
+    let (ChunkPos(chunk_pos), LocalVoxelPos(local)) = LocalVoxelPos::split(pos)?;
+    let point = PointN(local.into());
+    let previous = {
+      let (mut chunk, state) = chunks.get_mut(*self.loaded_chunks.get(&chunk_pos)?).ok()?;
+      if !state.has_voxels() {
+        return None;
+      }
+      let previous = chunk.block_data.get(point);
+      if previous == voxel {
+        return Some(previous);
+      }
+      chunk.block_data.set(point, voxel);
+      chunk.modified = true;
+      previous
+    };
+
+    // Keep the padding of neighbouring chunks in sync. Neighbours without
+    // voxels get their padding once they are generated.
+    for side in neighbour_offsets().iter() {
+      let neighbour_local = IVec3::new(
+        local.x - side.x * CHUNK_SIZE_X,
+        local.y,
+        local.z - side.y * CHUNK_SIZE_Z,
+      );
+      if !ChunkVoxels::extent().contains(PointN(neighbour_local.into())) {
+        continue;
+      }
+
+      if let Some(entity) = self.loaded_chunks.get(&(chunk_pos + *side)) {
+        if let Ok((mut neighbour, state)) = chunks.get_mut(*entity) {
+          if !state.has_voxels() {
+            continue;
+          }
+          neighbour
+            .block_data
+            .set(PointN(neighbour_local.into()), voxel);
+        }
+      }
+    }
+
+    let section_y = local.y.div_euclid(SECTION_HEIGHT);
+    let section_pos = IVec3::new(chunk_pos.x, section_y, chunk_pos.y);
+    remesh_requests.send(RemeshSectionRequest(section_pos));
+
+    let mut send_neighbour = |on_border: bool, offset: IVec3| {
+      let neighbour_pos = section_pos + offset;
+      if on_border && neighbour_pos.y >= 0 && neighbour_pos.y < SECTIONS_PER_CHUNK {
+        remesh_requests.send(RemeshSectionRequest(neighbour_pos));
+      }
+    };
+    // Downsampled padding cells of neighbours meshed at a lower level of
+    // detail cover more than the border voxel
+    let section_local_y = local.y.rem_euclid(SECTION_HEIGHT);
+    send_neighbour(local.x < MAX_LOD_SCALE, IVec3::new(-1, 0, 0));
+    send_neighbour(local.x >= CHUNK_SIZE_X - MAX_LOD_SCALE, IVec3::new(1, 0, 0));
+    send_neighbour(local.z < MAX_LOD_SCALE, IVec3::new(0, 0, -1));
+    send_neighbour(local.z >= CHUNK_SIZE_Z - MAX_LOD_SCALE, IVec3::new(0, 0, 1));
+    send_neighbour(section_local_y < MAX_LOD_SCALE, IVec3::new(0, -1, 0));
+    send_neighbour(
+      section_local_y >= SECTION_HEIGHT - MAX_LOD_SCALE,
+      IVec3::new(0, 1, 0),
+    );
+
+    Some(previous)
+  }
 }
 
 #[derive(Component)]
 pub struct Chunk {
   pub pos: IVec2,
-  pub block_data: Array3x1<Voxel>,
+  pub block_data: ChunkVoxels,
+  /// Set when the chunk differs from what the generator would produce and
+  /// has to be saved before unloading
+  pub modified: bool,
+}
+
+/// Generation in progress on the `AsyncComputeTaskPool`. Dropping the
+/// component (e.g. by despawning the chunk) cancels the task.
+#[derive(Component)]
+struct ChunkGenerationTask(Task<(ChunkVoxels, Duration)>);
+
+/// Loads chunks within `radius` (in chunks) around the entity and keeps
+/// them loaded up to `unload_radius`. The world loads the union of all
+/// loader radii and unloads chunks only when they are outside of every
+/// loader's `unload_radius` for `ChunkStreamingConfig::unload_delay`. An
+/// `unload_radius` smaller than `radius` is treated as `radius`.
+///
+/// Moving loaders stretch both radii along their travel vector, so chunks
+/// are requested before the loader reaches them.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct ChunkLoader {
+  pub radius: i32,
+  pub unload_radius: i32,
+}
+
+/// Time constant of the loader velocity smoothing, in seconds
+const LOADER_VELOCITY_SMOOTHING: f32 = 0.25;
+
+/// Recent velocity of a `ChunkLoader`, added to loaders automatically
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct ChunkLoaderMotion {
+  velocity: Vec3,
+  last_position: Vec3,
+}
+
+impl ChunkLoaderMotion {
+  /// Smoothed velocity in world units per second
+  #[inline]
+  pub fn velocity(&self) -> Vec3 {
+    self.velocity
+  }
+
+  /// Offset in chunks the loader is expected to have moved by after
+  /// `lookahead` seconds, at most `max_lookahead` chunks long
+  pub fn lookahead(&self, lookahead: f32, max_lookahead: f32) -> Vec2 {
+    let ahead = Vec2::new(self.velocity.x, self.velocity.z) * lookahead / CHUNK_SIZE_X as f32;
+    if ahead.length() > max_lookahead {
+      ahead.normalize() * max_lookahead
+    } else {
+      ahead
+    }
+  }
+}
+
+/// Squared distance of `offset` from the segment between the origin and
+/// `ahead`
+#[inline]
+fn distance_to_travel_squared(offset: Vec2, ahead: Vec2) -> f32 {
+  let length_squared = ahead.length_squared();
+  let t = if length_squared > 0.0 {
+    (offset.dot(ahead) / length_squared).clamp(0.0, 1.0)
+  } else {
+    0.0
+  };
+  (offset - ahead * t).length_squared()
+}
+
+/// Keeps the `ChunkLoaderMotion` of every loader up to date
+fn track_chunk_loader_motion(
+  mut commands: Commands,
+  time: Res<Time>,
+  mut loaders: Query<(Entity, &GlobalTransform, Option<&mut ChunkLoaderMotion>), With<ChunkLoader>>,
+) {
+  let delta = time.delta_seconds();
+  let smoothing = 1.0 - (-delta / LOADER_VELOCITY_SMOOTHING).exp();
+
+  for (entity, transform, motion) in loaders.iter_mut() {
+    let position = transform.translation;
+    match motion {
+      Some(mut motion) => {
+        if delta > 0.0 {
+          let velocity = (position - motion.last_position) / delta;
+          motion.velocity += (velocity - motion.velocity) * smoothing;
+        }
+        motion.last_position = position;
+      }
+      None => {
+        commands.entity(entity).insert(ChunkLoaderMotion {
+          velocity: Vec3::ZERO,
+          last_position: position,
+        });
+      }
+    }
+  }
 }
 
 #[derive(Bundle)]
@@ -80,92 +303,280 @@ pub struct ChunkDataBundle {
   pub chunk: Chunk,
 }
 
+/// `SECTION_HEIGHT` tall slice of a chunk column, spawned as a child of the
+/// `Chunk` entity. Voxels stay in the column, sections are meshed (and
+/// remeshed) independently.
+#[derive(Component, Debug)]
+pub struct ChunkSection {
+  /// Chunk position in `x` and `z`, section index in `y`
+  pub pos: IVec3,
+}
+
+impl ChunkSection {
+  #[inline]
+  pub fn chunk_pos(&self) -> IVec2 {
+    IVec2::new(self.pos.x, self.pos.z)
+  }
+
+  #[inline]
+  pub fn section_y(&self) -> i32 {
+    self.pos.y
+  }
+}
+
+#[derive(Bundle)]
+pub struct ChunkSectionBundle {
+  pub section: ChunkSection,
+  pub transform: Transform,
+  pub global_transform: GlobalTransform,
+}
+
+/// Requests chunks within range of any loader and unloads chunks out of
+/// range. The range of moving loaders is extended along their travel
+/// vector by `ChunkStreamingConfig::lookahead`.
 fn update_visible_chunks(
-  player_query: Query<(&Player, &Transform)>,
-  player_config: Res<PlayerConfig>,
+  loader_query: Query<(&ChunkLoader, &GlobalTransform, Option<&ChunkLoaderMotion>)>,
   world: Res<VoxelWorld>,
+  time: Res<Time>,
+  streaming_config: Res<ChunkStreamingConfig>,
+  mut out_of_range_since: Local<HashMap<IVec2, f64>>,
   mut spawn_requests: EventWriter<ChunkSpawnRequest>,
   mut despawn_requests: EventWriter<ChunkDespawnRequest>,
 ) {
-  for (player, transform) in player_query.iter() {
-    let current_chunk_pos = get_chunk_indices(transform.translation);
+  let loaders: Vec<(IVec2, ChunkLoader, Vec2)> = loader_query
+    .iter()
+    .map(|(loader, transform, motion)| {
+      let ahead = motion.map_or(Vec2::ZERO, |motion| {
+        motion.lookahead(streaming_config.lookahead, streaming_config.max_lookahead)
+      });
+      (WorldPos(transform.translation).chunk().0, *loader, ahead)
+    })
+    .collect();
 
-    let mut load_radius_chunks: Vec<IVec2> = Vec::new();
-    let max_distance = player_config.chunk_render_distance as i32;
-
-    for dx in -max_distance..=max_distance {
-      for dy in -max_distance..=max_distance {
-        // Skip chunks out of render_distance radius
-        if dx.pow(2) + dy.pow(2) >= max_distance.pow(2) {
+  let mut load_radius_chunks: HashSet<IVec2> = HashSet::default();
+  for (current_chunk_pos, loader, ahead) in loaders.iter() {
+    let max_distance = loader.radius;
+    let min = ahead.min(Vec2::ZERO).floor().as_i32() - IVec2::splat(max_distance);
+    let max = ahead.max(Vec2::ZERO).ceil().as_i32() + IVec2::splat(max_distance);
+    for dx in min.x..=max.x {
+      for dy in min.y..=max.y {
+        // Skip chunks out of the loader radius around the travel vector
+        let offset = Vec2::new(dx as f32, dy as f32);
+        if distance_to_travel_squared(offset, *ahead) >= max_distance.pow(2) as f32 {
           continue;
         }
 
-        let chunk_pos = current_chunk_pos + IVec2::new(dx, dy);
+        let chunk_pos = *current_chunk_pos + IVec2::new(dx, dy);
         if !world.loaded_chunks.contains_key(&chunk_pos) {
-          load_radius_chunks.push(chunk_pos);
+          load_radius_chunks.insert(chunk_pos);
         }
       }
     }
+  }
 
-    load_radius_chunks.sort_by_key(|a| -(a.x.pow(2) + a.y.pow(2)));
-    // println!("load radius chunks: {:?}", load_radius_chunks);
+  // Load order is decided by the `ChunkLoadQueue`
+  spawn_requests.send_batch(load_radius_chunks.into_iter().map(ChunkSpawnRequest));
 
-    spawn_requests.send_batch(
-      load_radius_chunks
-        .into_iter()
-        .map(|c| ChunkSpawnRequest(c.clone())),
-    );
+  // Chunks are kept as long as any loader reaches them, and unloaded once
+  // they have been out of range for the unload delay
+  let now = time.seconds_since_startup();
+  for (key, entity) in world.loaded_chunks.iter() {
+    let in_range = loaders.iter().any(|(current_chunk_pos, loader, ahead)| {
+      let delta = (*key - *current_chunk_pos).as_f32();
+      // Unloading closer than the load radius would reload the chunk right
+      // away
+      let unload_radius = loader.unload_radius.max(loader.radius);
+      distance_to_travel_squared(delta, *ahead) <= unload_radius.pow(2) as f32
+    });
+    if in_range {
+      out_of_range_since.remove(key);
+      continue;
+    }
+
+    let since = *out_of_range_since.entry(*key).or_insert(now);
+    if now - since >= streaming_config.unload_delay as f64 {
+      despawn_requests.send(ChunkDespawnRequest(*key, *entity));
+    }
+  }
+  out_of_range_since.retain(|key, _| world.loaded_chunks.contains_key(key));
+}
+
+/// The four horizontal neighbours of a chunk as chunk offsets, ordered -x,
+/// +x, -z, +z
+pub(crate) fn neighbour_offsets() -> [IVec2; 4] {
+  [
+    IVec2::new(-1, 0),
+    IVec2::new(1, 0),
+    IVec2::new(0, -1),
+    IVec2::new(0, 1),
+  ]
+}
+
+/// Exchanges border voxels between newly generated chunks and their loaded
+/// neighbours, so the mesher culls faces between them instead of emitting a
+/// wall at every chunk border. While a neighbour isn't loaded, the padding
+/// towards it is empty and closes the mesh off. Neighbours are remeshed as
+/// their padding changed.
+fn sync_chunk_borders(
+  world: Res<VoxelWorld>,
+  mut state_events: EventReader<ChunkStateChanged>,
+  mut chunks: Query<(&mut Chunk, &ChunkState)>,
+  mut remesh_requests: EventWriter<RemeshSectionRequest>,
+) {
+  for state_event in state_events.iter() {
+    if state_event.to != ChunkState::Generated {
+      continue;
+    }
+    let chunk_pos = state_event.pos;
+
+    for side in neighbour_offsets().iter() {
+      let neighbour_pos = chunk_pos + *side;
+      let neighbour_entity = match world.loaded_chunks.get(&neighbour_pos) {
+        Some(entity) => *entity,
+        None => continue,
+      };
 
-    for key in world.loaded_chunks.keys() {
-      let delta = *key - current_chunk_pos;
-      let entity = world.loaded_chunks.get(key).unwrap().clone();
-      if delta.x.pow(2) + delta.y.pow(2) > player_config.chunk_render_distance.pow(2).into() {
-        despawn_requests.send(ChunkDespawnRequest(key.clone(), entity));
+      let neighbour_border = match chunks.get_mut(neighbour_entity) {
+        Ok((neighbour, state)) if state.has_voxels() => neighbour.block_data.border(-*side),
+        _ => continue,
+      };
+      let border = match chunks.get_mut(state_event.entity) {
+        Ok((mut chunk, _)) => {
+          chunk.block_data.set_padding(*side, &neighbour_border);
+          chunk.block_data.border(*side)
+        }
+        Err(_) => break,
+      };
+      if let Ok((mut neighbour, _)) = chunks.get_mut(neighbour_entity) {
+        neighbour.block_data.set_padding(-*side, &border);
+      }
+
+      for section_y in 0..SECTIONS_PER_CHUNK {
+        remesh_requests.send(RemeshSectionRequest(IVec3::new(
+          neighbour_pos.x,
+          section_y,
+          neighbour_pos.y,
+        )));
       }
     }
   }
 }
 
+/// Empties the padding of loaded chunks towards unloading neighbours, so
+/// their border faces close the mesh off again, and remeshes them.
+///
+/// The padding isn't filled from the generator instead: neighbours that
+/// aren't loaded can hold edits saved to disk, and generating them just for
+/// their border would cost a full chunk generation per side.
+fn clear_unloaded_borders(
+  world: Res<VoxelWorld>,
+  mut state_events: EventReader<ChunkStateChanged>,
+  mut chunks: Query<(&mut Chunk, &ChunkState)>,
+  mut remesh_requests: EventWriter<RemeshSectionRequest>,
+) {
+  for state_event in state_events.iter() {
+    if state_event.to != ChunkState::Unloading || !state_event.from.has_voxels() {
+      continue;
+    }
+
+    for side in neighbour_offsets().iter() {
+      let neighbour_pos = state_event.pos + *side;
+      let neighbour_entity = match world.loaded_chunks.get(&neighbour_pos) {
+        Some(entity) => *entity,
+        None => continue,
+      };
+
+      match chunks.get_mut(neighbour_entity) {
+        Ok((mut neighbour, state)) if state.has_voxels() => {
+          neighbour.block_data.clear_padding(-*side);
+        }
+        _ => continue,
+      }
+
+      for section_y in 0..SECTIONS_PER_CHUNK {
+        remesh_requests.send(RemeshSectionRequest(IVec3::new(
+          neighbour_pos.x,
+          section_y,
+          neighbour_pos.y,
+        )));
+      }
+    }
+  }
+}
+
+/// Moves chunks out of range to `Unloading`, dropping their pending
+/// generation requests and cancelling generation in flight. Voxels of
+/// generated chunks are kept in the `ChunkCache`.
 fn prepare_for_unload(
+  mut commands: Commands,
   mut despawn_events: EventReader<ChunkDespawnRequest>,
-  mut chunks: Query<&mut ChunkLoadState>,
+  mut chunks: Query<(&Chunk, &mut ChunkState)>,
+  mut load_queue: ResMut<ChunkLoadQueue>,
+  mut cache: ResMut<ChunkCache>,
+  mut state_events: EventWriter<ChunkStateChanged>,
 ) {
   for despawn_event in despawn_events.iter() {
-    if let Ok(mut load_state) = chunks.get_mut(despawn_event.1) {
-      *load_state = ChunkLoadState::Unload;
+    let ChunkDespawnRequest(chunk_pos, entity) = *despawn_event;
+    if let Ok((chunk, mut state)) = chunks.get_mut(entity) {
+      if *state == ChunkState::Unloading {
+        continue;
+      }
+      if state.has_voxels() {
+        cache.insert(chunk_pos, chunk.block_data.clone());
+      }
+
+      state.transition(ChunkState::Unloading, entity, chunk_pos, &mut state_events);
+      load_queue.remove(entity);
+      commands.entity(entity).remove::<ChunkGenerationTask>();
     }
   }
 }
 
+fn save_chunk(storage: &RegionStorage, chunk: &Chunk) {
+  if let Err(err) = storage.save(chunk.pos, &chunk.block_data) {
+    warn!("Failed to save chunk {:?}: {}", chunk.pos, err);
+  }
+}
+
 fn destroy_chunks(
   mut commands: Commands,
   mut world: ResMut<VoxelWorld>,
-  chunks: Query<(&Chunk, &ChunkLoadState)>,
+  storage: Option<Res<RegionStorage>>,
+  chunks: Query<(&Chunk, &ChunkState)>,
 ) {
-  for (chunk, load_state) in chunks.iter() {
-    match load_state {
-      ChunkLoadState::Unload => {
+  for (chunk, state) in chunks.iter() {
+    match state {
+      ChunkState::Unloading => {
+        if let Some(storage) = storage.as_deref().filter(|_| chunk.modified) {
+          save_chunk(storage, chunk);
+        }
         let entity = world.loaded_chunks.remove(&chunk.pos).unwrap();
-        commands.entity(entity).despawn();
+        for section_y in 0..SECTIONS_PER_CHUNK {
+          world
+            .loaded_sections
+            .remove(&IVec3::new(chunk.pos.x, section_y, chunk.pos.y));
+        }
+        commands.entity(entity).despawn_recursive();
       }
       _ => {}
     }
   }
 }
 
-fn get_chunk_indices(pos: Vec3) -> IVec2 {
-  IVec2::new(
-    pos.x.floor() as i32 / CHUNK_SIZE_X,
-    pos.z.floor() as i32 / CHUNK_SIZE_Z,
-  )
-}
+fn save_chunks_on_exit(
+  mut exit_events: EventReader<AppExit>,
+  storage: Option<Res<RegionStorage>>,
+  chunks: Query<&Chunk>,
+) {
+  if exit_events.iter().next().is_none() {
+    return;
+  }
 
-fn get_global_chunk_coordinates(coords: IVec2) -> Vec3 {
-  Vec3::new(
-    (coords.x * CHUNK_SIZE_X) as f32,
-    0.0,
-    (coords.y * CHUNK_SIZE_Z) as f32,
-  )
+  if let Some(storage) = storage {
+    for chunk in chunks.iter().filter(|chunk| chunk.modified) {
+      save_chunk(&storage, chunk);
+    }
+  }
 }
 
 fn create_chunks(
@@ -174,60 +585,156 @@ fn create_chunks(
   mut world: ResMut<VoxelWorld>,
 ) {
   for creation_request in spawn_events.iter() {
+    let chunk_pos = creation_request.0;
+    let mut sections = Vec::with_capacity(SECTIONS_PER_CHUNK as usize);
+
     let entity = commands
       .spawn_bundle(ChunkDataBundle {
-        transform: Transform::from_translation(get_global_chunk_coordinates(creation_request.0)),
+        transform: Transform::from_translation(ChunkPos(chunk_pos).world_origin().0),
         chunk: Chunk {
-          pos: creation_request.0,
-          block_data: Array3x1::fill(chunk_extent().padded(1), Voxel::default()),
+          pos: chunk_pos,
+          block_data: ChunkVoxels::new(Voxel::default()),
+          modified: false,
         },
         global_transform: Default::default(),
       })
-      .insert(ChunkLoadState::Load)
+      .insert(ChunkState::Requested)
+      .with_children(|parent| {
+        for section_y in 0..SECTIONS_PER_CHUNK {
+          let pos = IVec3::new(chunk_pos.x, section_y, chunk_pos.y);
+          let section = parent
+            .spawn_bundle(ChunkSectionBundle {
+              section: ChunkSection { pos },
+              transform: Transform::from_xyz(0.0, (section_y * SECTION_HEIGHT) as f32, 0.0),
+              global_transform: Default::default(),
+            })
+            .id();
+          sections.push((pos, section));
+        }
+      })
       .id();
 
-    world.loaded_chunks.insert(creation_request.0, entity);
+    world.loaded_chunks.insert(chunk_pos, entity);
+    world.loaded_sections.extend(sections);
   }
 }
 
 fn load_chunk_data(
-  mut chunks: Query<(&mut ChunkLoadState, Entity), Added<Chunk>>,
-  mut gen_requests: ResMut<VecDeque<ChunkLoadRequest>>,
+  chunks: Query<(&Chunk, &ChunkState, Entity), Added<Chunk>>,
+  mut load_queue: ResMut<ChunkLoadQueue>,
 ) {
-  for (mut load_state, entity) in chunks.iter_mut() {
-    match *load_state {
-      ChunkLoadState::Load => {
-        *load_state = ChunkLoadState::Generate;
-        gen_requests.push_front(ChunkLoadRequest(entity));
-      }
-      _ => continue,
+  for (chunk, state, entity) in chunks.iter() {
+    if let ChunkState::Requested = state {
+      load_queue.push(entity, chunk.pos);
     }
   }
 }
 
+/// Dispatches queued generation requests to the async compute pool. Chunks
+/// in the `ChunkCache` are restored right away, chunks saved in the
+/// `RegionStorage` are loaded from disk and the rest is generated. The
+/// resulting voxels are moved into the chunk by `apply_generated_chunks`.
+/// Only as many chunks as the `ChunkWorkScheduler` allows are dispatched
+/// per frame, with restores charged by the time they take on the main
+/// thread.
 fn generate_chunks(
-  player_config: Res<PlayerConfig>,
-  mut query: Query<(&mut Chunk, &mut ChunkLoadState)>,
-  mut gen_requests: ResMut<VecDeque<ChunkLoadRequest>>,
+  mut commands: Commands,
+  generator: Res<WorldGenerator>,
+  storage: Option<Res<RegionStorage>>,
+  task_pool: Res<AsyncComputeTaskPool>,
+  mut cache: ResMut<ChunkCache>,
+  mut query: Query<(&mut Chunk, &mut ChunkState)>,
+  mut load_queue: ResMut<ChunkLoadQueue>,
+  mut scheduler: ResMut<ChunkWorkScheduler>,
+  mut state_events: EventWriter<ChunkStateChanged>,
 ) {
-  for _ in 0..(player_config.chunk_render_distance / 2) {
-    if let Some(ev) = gen_requests.pop_front() {
-      if let Ok((mut data, mut load_state)) = query.get_mut(ev.0) {
-        generate_chunk(data);
-        *load_state = ChunkLoadState::Done;
+  let mut restored = 0;
+  let mut deferred = Vec::new();
+
+  while scheduler.has_budget(ChunkWork::Generation) {
+    let entity = match load_queue.pop() {
+      Some(entity) => entity,
+      None => break,
+    };
+
+    if let Ok((mut chunk, mut state)) = query.get_mut(entity) {
+      if *state != ChunkState::Requested {
+        continue;
       }
+
+      let chunk_pos = chunk.pos;
+      if restored == MAX_CACHE_RESTORES_PER_FRAME && cache.contains(chunk_pos) {
+        deferred.push((entity, chunk_pos));
+        continue;
+      }
+      if let Some(mut voxels) = cache.take(chunk_pos) {
+        let start = Instant::now();
+        // Padding is outdated, `sync_chunk_borders` copies it again from the
+        // neighbours that are still loaded
+        for side in neighbour_offsets().iter() {
+          voxels.clear_padding(*side);
+        }
+        chunk.block_data = voxels;
+        state.transition(ChunkState::Loading, entity, chunk_pos, &mut state_events);
+        state.transition(ChunkState::Generated, entity, chunk_pos, &mut state_events);
+        scheduler.spend_measured(ChunkWork::Generation, start.elapsed());
+        restored += 1;
+        continue;
+      }
+
+      let generator = generator.0.clone();
+      let storage = storage.as_deref().cloned();
+
+      let task = task_pool.spawn(async move {
+        let start = Instant::now();
+        let mut voxels = ChunkVoxels::new(Voxel::default());
+        if let Some(storage) = storage {
+          match storage.load(chunk_pos, &mut voxels) {
+            Ok(true) => return (voxels, start.elapsed()),
+            Ok(false) => {}
+            Err(err) => warn!("Failed to load chunk {:?}: {}", chunk_pos, err),
+          }
+        }
+
+        generator.generate(chunk_pos, &mut voxels);
+        voxels.optimize();
+        (voxels, start.elapsed())
+      });
+
+      commands.entity(entity).insert(ChunkGenerationTask(task));
+      scheduler.spend(ChunkWork::Generation);
+      state.transition(ChunkState::Loading, entity, chunk_pos, &mut state_events);
     }
   }
+
+  for (entity, chunk_pos) in deferred {
+    load_queue.push(entity, chunk_pos);
+  }
+  scheduler.set_queue_len(ChunkWork::Generation, load_queue.len());
 }
 
-fn mark_chunks_ready(
-  mut ready_events: EventWriter<ChunkReadyEvent>,
-  chunks: Query<(&Chunk, &ChunkLoadState, Entity), Changed<ChunkLoadState>>,
+fn apply_generated_chunks(
+  mut commands: Commands,
+  mut query: Query<(
+    Entity,
+    &mut Chunk,
+    &mut ChunkState,
+    &mut ChunkGenerationTask,
+  )>,
+  mut scheduler: ResMut<ChunkWorkScheduler>,
+  mut state_events: EventWriter<ChunkStateChanged>,
 ) {
-  for (chunk, load_state, entity) in chunks.iter() {
-    match load_state {
-      ChunkLoadState::Done => ready_events.send(ChunkReadyEvent(chunk.pos, entity)),
-      _ => {}
+  for (entity, mut chunk, mut state, mut task) in query.iter_mut() {
+    if let Some((voxels, duration)) = future::block_on(future::poll_once(&mut task.0)) {
+      commands.entity(entity).remove::<ChunkGenerationTask>();
+      scheduler.record(ChunkWork::Generation, duration);
+
+      // Chunk could have been marked for unloading while it was generated
+      if let ChunkState::Loading = *state {
+        chunk.block_data = voxels;
+        let chunk_pos = chunk.pos;
+        state.transition(ChunkState::Generated, entity, chunk_pos, &mut state_events);
+      }
     }
   }
 }
@@ -236,15 +743,64 @@ pub struct VoxelWorldPlugin;
 
 impl Plugin for VoxelWorldPlugin {
   fn build(&self, app: &mut App) {
+    const TRACK_LOADER_MOTION_LABEL: &'static str = "track_chunk_loader_motion";
     const UPDATE_VISIBLE_CHUNKS_LABEL: &'static str = "update_visible_chunks";
     const CREATE_CHUNKS_LABEL: &'static str = "create_chunks";
+    const PREPARE_FOR_UNLOAD_LABEL: &'static str = "prepare_for_unload";
+    const LOAD_CHUNK_DATA_LABEL: &'static str = "load_chunk_data";
+    const PRIORITISE_CHUNK_LOADS_LABEL: &'static str = "prioritise_chunk_loads";
+
+    app.init_resource::<WorldGenConfig>();
+    let custom_generator = app.world.get_resource::<WorldGenerator>().is_some();
+    if !custom_generator {
+      let generator =
+        WorldGenerator::from_config(app.world.get_resource::<WorldGenConfig>().unwrap());
+      app.insert_resource(generator);
+    }
+
+    app.init_resource::<ChunkStreamingConfig>();
+    let cache_capacity = app
+      .world
+      .get_resource::<ChunkStreamingConfig>()
+      .unwrap()
+      .cache_capacity;
+
+    // Saves are keyed by the generator settings, which say nothing about a
+    // custom generator, so its worlds are only saved to a `RegionStorage`
+    // inserted along with it
+    if app.world.get_resource::<RegionStorage>().is_none() {
+      if custom_generator {
+        warn!("Custom world generator without a RegionStorage, chunks won't be saved");
+      } else {
+        let config = app.world.get_resource::<WorldGenConfig>().unwrap();
+        match RegionStorage::for_world(config) {
+          Ok(storage) => {
+            app.insert_resource(storage);
+          }
+          Err(err) => warn!(
+            "Failed to open the world save, chunks won't be saved: {}",
+            err
+          ),
+        }
+      }
+    }
 
     app
       .insert_resource(VoxelWorld::default())
-      .init_resource::<VecDeque<ChunkLoadRequest>>()
+      .insert_resource(ChunkCache::new(cache_capacity))
+      .init_resource::<BlockRegistry>()
+      .add_asset::<BlockDefinitions>()
+      .init_asset_loader::<BlockDefinitionsLoader>()
+      .add_startup_system(load_block_definitions.system())
+      .add_system(update_block_registry.system())
+      .init_resource::<ChunkLoadQueue>()
       .add_event::<ChunkSpawnRequest>()
       .add_event::<ChunkDespawnRequest>()
-      .add_event::<ChunkReadyEvent>()
+      .add_event::<ChunkStateChanged>()
+      .add_event::<RemeshSectionRequest>()
+      .init_resource::<ChunkWorkScheduler>()
+      .add_startup_system(setup_scheduler_diagnostics.system())
+      .add_system_to_stage(CoreStage::First, plan_chunk_work.system())
       .add_stage(WorldUpdateStage::Update, SystemStage::parallel())
       .add_stage_after(
         WorldUpdateStage::Update,
@@ -256,11 +812,18 @@ impl Plugin for VoxelWorldPlugin {
         WorldUpdateStage::Cleanup,
         SystemStage::parallel(),
       )
+      .add_system_to_stage(
+        WorldUpdateStage::Update,
+        track_chunk_loader_motion
+          .system()
+          .label(TRACK_LOADER_MOTION_LABEL),
+      )
       .add_system_to_stage(
         WorldUpdateStage::Update,
         update_visible_chunks
           .system()
-          .label(UPDATE_VISIBLE_CHUNKS_LABEL),
+          .label(UPDATE_VISIBLE_CHUNKS_LABEL)
+          .after(TRACK_LOADER_MOTION_LABEL),
       )
       .add_system_to_stage(
         WorldUpdateStage::Update,
@@ -271,11 +834,39 @@ impl Plugin for VoxelWorldPlugin {
       )
       .add_system_to_stage(
         WorldUpdateStage::Update,
-        load_chunk_data.system().after(CREATE_CHUNKS_LABEL),
+        load_chunk_data
+          .system()
+          .label(LOAD_CHUNK_DATA_LABEL)
+          .after(CREATE_CHUNKS_LABEL),
+      )
+      .add_system_to_stage(
+        WorldUpdateStage::Update,
+        prioritise_chunk_loads
+          .system()
+          .label(PRIORITISE_CHUNK_LOADS_LABEL)
+          .after(TRACK_LOADER_MOTION_LABEL)
+          .after(LOAD_CHUNK_DATA_LABEL),
+      )
+      .add_system_to_stage(
+        WorldUpdateStage::Update,
+        generate_chunks.system().after(PRIORITISE_CHUNK_LOADS_LABEL),
+      )
+      .add_system_to_stage(WorldUpdateStage::Update, apply_generated_chunks.system())
+      .add_system_to_stage(WorldUpdateStage::PostUpdate, sync_chunk_borders.system())
+      .add_system_to_stage(
+        WorldUpdateStage::Cleanup,
+        prepare_for_unload.system().label(PREPARE_FOR_UNLOAD_LABEL),
+      )
+      .add_system_to_stage(
+        WorldUpdateStage::Cleanup,
+        clear_unloaded_borders
+          .system()
+          .after(PREPARE_FOR_UNLOAD_LABEL),
+      )
+      .add_system_to_stage(
+        WorldUpdateStage::Cleanup,
+        destroy_chunks.system().after(PREPARE_FOR_UNLOAD_LABEL),
       )
-      .add_system_to_stage(WorldUpdateStage::Update, generate_chunks.system())
-      .add_system_to_stage(WorldUpdateStage::Update, mark_chunks_ready.system())
-      .add_system_to_stage(WorldUpdateStage::Cleanup, prepare_for_unload.system())
-      .add_system_to_stage(WorldUpdateStage::Cleanup, destroy_chunks.system());
+      .add_system_to_stage(WorldUpdateStage::Cleanup, save_chunks_on_exit.system());
   }
 }